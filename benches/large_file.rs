@@ -0,0 +1,184 @@
+// Times opening, editing and saving a large file, first on the lines directly
+// and then with commands run by the editor, which adds undo history and swap
+// files, along with the peak memory used so far. Run with `cargo bench`. The
+// file size in megabytes can be changed with POE_BENCH_MB, it defaults to 1024.
+
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use poe::editor::Editor;
+use poe::format::FileFormat;
+use poe::line::{Line, LineRef};
+use poe::text::Text;
+
+const EDITS: usize = 1000;
+
+fn main() {
+    let size_mb: usize = env::var("POE_BENCH_MB")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(1024);
+
+    let path = env::temp_dir().join(format!("poe-bench-{}.txt", std::process::id()));
+    let lines = write_file(&path, size_mb).expect("Failed to write test file");
+    println!("{} MB, {} lines", size_mb, lines);
+
+    let start = Instant::now();
+    let reader = BufReader::with_capacity(1 << 16, File::open(&path).unwrap());
+    let (mut text, format) = FileFormat::read(reader, None).unwrap();
+    report("open", start.elapsed(), 1);
+    assert_eq!(text.len(), lines);
+
+    // Pseudo-random line numbers spread over the whole file
+    let mut seed = 0x2545_f491_4f6c_dd1du64;
+    let mut random_line = |len: usize| {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        (seed % len as u64) as usize
+    };
+
+    let start = Instant::now();
+    for _ in 0..EDITS {
+        text.splice(0, 0, Text::from(vec![Line::from("inserted at the top")]));
+    }
+    report("insert line at top", start.elapsed(), EDITS);
+
+    let start = Instant::now();
+    for _ in 0..EDITS {
+        text.splice(0, 1, Text::new());
+    }
+    report("delete line at top", start.elapsed(), EDITS);
+
+    let start = Instant::now();
+    for _ in 0..EDITS {
+        let line = random_line(text.len());
+        let new_line = Line::join([text.line(line), LineRef::new(b"!", None)], "");
+        text.splice(line, 1, Text::from(vec![new_line]));
+    }
+    report("change random line", start.elapsed(), EDITS);
+
+    let start = Instant::now();
+    for _ in 0..EDITS {
+        let line = random_line(text.len() - 100);
+        let removed = text.splice(line, 100, Text::new());
+        text.splice(random_line(text.len()), 0, removed);
+    }
+    report("move 100 random lines", start.elapsed(), EDITS);
+
+    let start = Instant::now();
+    let found = text.iter().filter(|l| l.as_bytes().ends_with(b"!")).count();
+    report("scan all lines", start.elapsed(), 1);
+    assert!(found > 0);
+
+    let start = Instant::now();
+    format
+        .write_lines(&mut BufWriter::new(io::sink()), text.iter())
+        .unwrap();
+    report("save", start.elapsed(), 1);
+    drop(text);
+
+    bench_editor(&path);
+
+    let _ = fs::remove_file(&path);
+}
+
+// Run commands the way they're typed at the prompt
+fn bench_editor(path: &Path) {
+    let small = path.with_extension("small.txt");
+    fs::write(&small, "a small file\n").unwrap();
+    let mut editor = Editor::new_empty();
+    editor
+        .set_option("undo-depth", &(4 * EDITS).to_string())
+        .unwrap();
+
+    let start = Instant::now();
+    editor.add_buffer(path).unwrap();
+    report("editor: open", start.elapsed(), 1);
+
+    // The first change writes the whole buffer to the swap file, later ones
+    // only add the changed lines
+    time_commands(&mut editor, "first change (swap)", &["1d"]);
+    time_commands(&mut editor, "copy line (swap)", &["1t0"; EDITS]);
+    time_commands(&mut editor, "delete line (swap)", &["1d"; EDITS]);
+    time_commands(&mut editor, "undo (swap)", &["u"; EDITS]);
+
+    editor.execute("S swap-interval 0");
+    time_commands(&mut editor, "copy line (no swap)", &["1t0"; EDITS]);
+    time_commands(&mut editor, "delete line (no swap)", &["1d"; EDITS]);
+
+    // Reloading keeps the old contents for undo, opening another file doesn't
+    time_commands(&mut editor, "reload", &["e!"]);
+    time_commands(&mut editor, "undo reload", &["u"]);
+    time_commands(
+        &mut editor,
+        "open small file",
+        &[&format!("o! {}", small.display())],
+    );
+
+    editor.execute("Q");
+    let _ = fs::remove_file(&small);
+}
+
+fn time_commands(editor: &mut Editor, name: &str, commands: &[&str]) {
+    let start = Instant::now();
+    for cmd in commands {
+        editor.execute(cmd);
+    }
+    report(
+        &format!("editor: {}", name),
+        start.elapsed(),
+        commands.len(),
+    );
+}
+
+// Write a log-like file of about size_mb megabytes. Returns the number of lines.
+fn write_file(path: &Path, size_mb: usize) -> io::Result<usize> {
+    let mut w = BufWriter::new(File::create(path)?);
+    let mut written = 0;
+    let mut lines = 0;
+
+    while written < size_mb << 20 {
+        let line = format!(
+            "2024-01-01T00:00:{:02}Z INFO request {} served in {} ms\n",
+            lines % 60,
+            lines,
+            lines % 997
+        );
+        w.write_all(line.as_bytes())?;
+        written += line.len();
+        lines += 1;
+    }
+
+    w.flush()?;
+    Ok(lines)
+}
+
+fn report(name: &str, elapsed: Duration, count: usize) {
+    let time = if count == 1 {
+        format!("{:?}", elapsed)
+    } else {
+        format!("{:?} total, {:?} each", elapsed, elapsed / count as u32)
+    };
+    println!(
+        "{:>32}: {}, peak memory {} MB",
+        name,
+        time,
+        peak_memory_mb()
+    );
+}
+
+// The most memory the process has had resident at once
+fn peak_memory_mb() -> i64 {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) };
+    // Linux reports kilobytes, macOS bytes
+    if cfg!(target_os = "macos") {
+        usage.ru_maxrss >> 20
+    } else {
+        usage.ru_maxrss >> 10
+    }
+}
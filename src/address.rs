@@ -0,0 +1,246 @@
+// Line addresses in the style of ed. Addresses are 1-based, 0 means "before the
+// first line" and is only accepted by commands that can make use of it.
+
+// An inclusive range of line addresses
+pub type Range = (usize, usize);
+
+pub trait Context {
+    fn current_line(&self) -> usize;
+    fn last_line(&self) -> usize;
+    fn mark(&self, name: char) -> Option<usize>;
+}
+
+// Parse the address prefix of a command line. Returns the resolved range, if any
+// address was given, and the remaining command text.
+pub fn parse_range<'a, C: Context>(
+    input: &'a str,
+    ctx: &C,
+) -> Result<(Option<Range>, &'a str), String> {
+    let mut rest = input.trim_start();
+
+    if let Some(r) = rest.strip_prefix('%') {
+        return Ok((Some((1, ctx.last_line())), r));
+    }
+
+    let mut current = ctx.current_line();
+    let mut addrs: Vec<usize> = Vec::with_capacity(2);
+    let mut last_sep = None;
+
+    loop {
+        let (addr, r) = parse_address(rest, current, ctx)?;
+        rest = r.trim_start();
+
+        let sep = match rest.chars().next() {
+            Some(c) if c == ',' || c == ';' => c,
+            _ => {
+                match (addr, last_sep) {
+                    (Some(a), _) => addrs.push(a),
+                    // A lone separator covers everything up to the last line
+                    (None, Some(_)) if addrs.len() == 1 && addr_defaulted(input) => {
+                        addrs.push(ctx.last_line())
+                    }
+                    (None, Some(_)) => addrs.push(*addrs.last().unwrap()),
+                    (None, None) => {}
+                }
+                break;
+            }
+        };
+
+        let a = match addr {
+            Some(a) => a,
+            None if addrs.is_empty() => {
+                if sep == ',' {
+                    1
+                } else {
+                    current
+                }
+            }
+            None => return Err("Invalid address".to_owned()),
+        };
+
+        if sep == ';' {
+            current = a;
+        }
+
+        addrs.push(a);
+        last_sep = Some(sep);
+        rest = &rest[1..];
+    }
+
+    let range = match addrs.len() {
+        0 => None,
+        1 => Some((addrs[0], addrs[0])),
+        n => Some((addrs[n - 2], addrs[n - 1])),
+    };
+
+    if let Some((start, end)) = range {
+        if start > end {
+            return Err("Invalid range".to_owned());
+        }
+    }
+
+    Ok((range, rest))
+}
+
+// True when the first address of a range was left out, as in "," or ";".
+fn addr_defaulted(input: &str) -> bool {
+    matches!(input.trim_start().chars().next(), Some(',') | Some(';'))
+}
+
+fn parse_address<'a, C: Context>(
+    input: &'a str,
+    current: usize,
+    ctx: &C,
+) -> Result<(Option<usize>, &'a str), String> {
+    let mut rest = input.trim_start();
+
+    let mut line = match rest.chars().next() {
+        Some('.') => {
+            rest = &rest[1..];
+            Some(current as isize)
+        }
+        Some('$') => {
+            rest = &rest[1..];
+            Some(ctx.last_line() as isize)
+        }
+        Some('\'') => {
+            let mut chars = rest[1..].chars();
+            let name = chars.next().ok_or_else(|| "Missing mark name".to_owned())?;
+            rest = chars.as_str();
+            match ctx.mark(name) {
+                Some(l) => Some(l as isize),
+                None => return Err(format!("Unknown mark '{}'", name)),
+            }
+        }
+        Some(c) if c.is_ascii_digit() => {
+            let (num, r) = parse_number(rest);
+            rest = r;
+            Some(num)
+        }
+        _ => None,
+    };
+
+    while let Some(c) = rest.chars().next() {
+        if c != '+' && c != '-' {
+            break;
+        }
+        rest = &rest[1..];
+
+        let offset = if rest.starts_with(|c: char| c.is_ascii_digit()) {
+            let (num, r) = parse_number(rest);
+            rest = r;
+            num
+        } else {
+            1
+        };
+
+        let base = line.unwrap_or(current as isize);
+        line = Some(if c == '+' {
+            base.saturating_add(offset)
+        } else {
+            base.saturating_sub(offset)
+        });
+    }
+
+    match line {
+        Some(l) if l < 0 || l > ctx.last_line() as isize => Err("Invalid address".to_owned()),
+        Some(l) => Ok((Some(l as usize), rest)),
+        None => Ok((None, rest)),
+    }
+}
+
+fn parse_number(input: &str) -> (isize, &str) {
+    let end = input
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(input.len());
+    (input[..end].parse().unwrap_or(isize::MAX), &input[end..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Ten lines, on line 5, with mark a on line 2 and b on line 8
+    struct Lines;
+
+    impl Context for Lines {
+        fn current_line(&self) -> usize {
+            5
+        }
+
+        fn last_line(&self) -> usize {
+            10
+        }
+
+        fn mark(&self, name: char) -> Option<usize> {
+            match name {
+                'a' => Some(2),
+                'b' => Some(8),
+                _ => None,
+            }
+        }
+    }
+
+    fn range(input: &str) -> Result<(Option<Range>, &str), String> {
+        parse_range(input, &Lines)
+    }
+
+    fn lines(input: &str) -> Range {
+        range(input).unwrap().0.unwrap()
+    }
+
+    #[test]
+    fn single_addresses() {
+        assert_eq!(range("").unwrap(), (None, ""));
+        assert_eq!(range("p").unwrap(), (None, "p"));
+        assert_eq!(range("3p").unwrap(), (Some((3, 3)), "p"));
+        assert_eq!(lines("."), (5, 5));
+        assert_eq!(lines("$"), (10, 10));
+        assert_eq!(lines("0"), (0, 0));
+        assert!(range("11").is_err());
+    }
+
+    #[test]
+    fn offsets() {
+        assert_eq!(lines("+"), (6, 6));
+        assert_eq!(lines("++"), (7, 7));
+        assert_eq!(lines("-2"), (3, 3));
+        assert_eq!(lines(".+3"), (8, 8));
+        assert_eq!(lines("$-1"), (9, 9));
+        assert_eq!(lines("2+2-1"), (3, 3));
+        assert!(range("-6").is_err());
+        assert!(range("$+1").is_err());
+    }
+
+    #[test]
+    fn marks() {
+        assert_eq!(lines("'a"), (2, 2));
+        assert_eq!(lines("'a,'b"), (2, 8));
+        assert_eq!(lines("'b-1"), (7, 7));
+        assert!(range("'z").is_err());
+        assert!(range("'").is_err());
+    }
+
+    #[test]
+    fn comma_ranges() {
+        assert_eq!(range("2,4d").unwrap(), (Some((2, 4)), "d"));
+        assert_eq!(lines("%"), (1, 10));
+        assert_eq!(lines(","), (1, 10));
+        assert_eq!(lines(",3"), (1, 3));
+        assert_eq!(lines("3,"), (3, 3));
+        assert_eq!(lines(" 2 , 4 "), (2, 4));
+        assert_eq!(lines("1,2,3"), (2, 3));
+        assert!(range("4,2").is_err());
+    }
+
+    // Addresses after ; are counted from the address before it rather than the
+    // current line
+    #[test]
+    fn semicolon_ranges() {
+        assert_eq!(lines(";"), (5, 10));
+        assert_eq!(lines(";+2"), (5, 7));
+        assert_eq!(lines("2;+2"), (2, 4));
+        assert_eq!(lines("2,+2"), (2, 7));
+        assert_eq!(lines("'a;."), (2, 2));
+    }
+}
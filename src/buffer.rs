@@ -0,0 +1,361 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Error};
+use std::path::{Path, PathBuf};
+
+use encoding_rs::Encoding;
+
+use crate::compress::Compression;
+use crate::file::{Crc, Stamp};
+use crate::format::FileFormat;
+use crate::line::Line;
+use crate::swap::Journal;
+use crate::text::Text;
+use crate::undo::{self, Change, History, Step};
+
+const READ_BUF_SIZE: usize = 1 << 16;
+
+// An open file and everything the editor tracks about it
+pub struct Buffer {
+    pub filename: Option<PathBuf>,
+    pub format: FileFormat,
+    pub contents: Text,
+    pub curr_line: u32,
+    pub modified: bool,
+    pub global_lines: Option<Vec<u32>>, // Lines still to be visited by a running global command
+    pub marks: BTreeMap<char, u32>,
+    pub history: History,
+    pub swap_file: Option<PathBuf>,
+    pub swap_pending: usize, // Changes not yet written to the swap file
+    pub swap_journal: Journal,
+    pub swap_failed: bool,
+    pub stamp: Option<Stamp>,  // The file as it was last read or written
+    pub lock: Option<PathBuf>, // Lock file held for the buffer's file
+    pub read_only: bool,       // Another session has the file locked
+}
+
+impl Buffer {
+    pub fn new() -> Buffer {
+        Buffer {
+            filename: None,
+            format: FileFormat::new(),
+            contents: Text::new(),
+            curr_line: 0,
+            modified: false,
+            global_lines: None,
+            marks: BTreeMap::new(),
+            history: History::new(undo::DEFAULT_DEPTH),
+            swap_file: None,
+            swap_pending: 0,
+            swap_journal: Journal::default(),
+            swap_failed: false,
+            stamp: None,
+            lock: None,
+            read_only: false,
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(
+        path: P,
+        encoding: Option<&'static Encoding>,
+    ) -> Result<Buffer, Error> {
+        if !path.as_ref().exists() {
+            let mut buffer = Self::new();
+            buffer.filename = Some(path.as_ref().to_owned());
+            return Ok(buffer);
+        }
+
+        let (contents, format, stamp) = read_file(path.as_ref(), encoding)?;
+
+        let mut buffer = Self::new();
+        buffer.filename = Some(path.as_ref().to_owned());
+        buffer.format = format;
+        buffer.contents = contents;
+        buffer.stamp = Some(stamp);
+        Ok(buffer)
+    }
+
+    // Name used to refer to the buffer in messages and the buffer list
+    pub fn name(&self) -> String {
+        match &self.filename {
+            Some(f) => f.display().to_string(),
+            None => "[No Name]".to_owned(),
+        }
+    }
+
+    pub fn set_current_line(&mut self, line: u32) {
+        self.curr_line = line;
+
+        if self.curr_line >= self.contents.len() as u32 {
+            self.curr_line = (self.contents.len() as u32).saturating_sub(1);
+        }
+    }
+
+    pub fn insert_line(&mut self, line: u32, text: Line) {
+        self.edit(line, 0, Text::from(vec![text]));
+    }
+
+    pub fn insert_lines(&mut self, line: u32, lines: Text) {
+        self.edit(line, 0, lines);
+    }
+
+    // Replace a line, keeping its line ending
+    pub fn set_line(&mut self, line: u32, mut text: Line) {
+        text.set_ending(self.contents.line(line as usize).ending());
+        self.edit(line, 1, Text::from(vec![text]));
+    }
+
+    // Give lines with their own line ending the file's one
+    pub fn clear_line_endings(&mut self) {
+        let lines: Vec<u32> = (self.contents.iter().enumerate())
+            .filter(|(_, l)| l.ending().is_some())
+            .map(|(i, _)| i as u32)
+            .collect();
+
+        for i in lines {
+            let mut line = self.contents.line(i as usize).to_line();
+            line.set_ending(None);
+            self.edit(i, 1, Text::from(vec![line]));
+        }
+    }
+
+    // Decode lines that were kept as invalid UTF-8 bytes in another encoding,
+    // for files read as UTF-8 that turn out to be in a legacy encoding. Lines
+    // that aren't valid in that encoding either are left alone and counted.
+    pub fn redecode(&mut self, encoding: &'static Encoding) -> usize {
+        let lines: Vec<u32> = (self.contents.iter().enumerate())
+            .filter(|(_, l)| std::str::from_utf8(l.as_bytes()).is_err())
+            .map(|(i, _)| i as u32)
+            .collect();
+
+        let mut failed = 0;
+        for i in lines {
+            let bytes = self.contents.line(i as usize).as_bytes();
+            match encoding.decode_without_bom_handling_and_without_replacement(bytes) {
+                Some(text) => {
+                    let line = Line::from(text.into_owned());
+                    self.set_line(i, line);
+                }
+                None => failed += 1,
+            }
+        }
+        failed
+    }
+
+    pub fn remove_lines(&mut self, start: u32, end: u32) -> Text {
+        let removed = self.splice(start, (end - start + 1) as usize, Text::new());
+        self.record_splice(start, 0, removed.clone());
+        removed
+    }
+
+    // Move lines start to end so they begin at line to, counted after the
+    // lines have been removed. Marks on the moved lines move with them.
+    pub fn move_lines(&mut self, start: u32, end: u32, to: u32) {
+        let moved_marks: Vec<(char, u32)> = self
+            .marks
+            .iter()
+            .filter(|(_, l)| **l >= start && **l <= end)
+            .map(|(name, l)| (*name, *l - start))
+            .collect();
+
+        let lines = self.remove_lines(start, end);
+        self.insert_lines(to, lines);
+
+        for (name, offset) in moved_marks {
+            self.marks.insert(name, to + offset);
+        }
+    }
+
+    // Replace count lines starting at line with lines and record the change so
+    // it can be undone.
+    pub fn edit(&mut self, line: u32, count: usize, lines: Text) {
+        let inserted = lines.len();
+        let removed = self.splice(line, count, lines);
+        self.record_splice(line, inserted, removed);
+    }
+
+    // The removed lines are moved into the undo history rather than copied,
+    // replacing a large buffer shouldn't need memory for it twice over
+    fn record_splice(&mut self, line: u32, inserted: usize, removed: Text) {
+        self.record(Change::Splice {
+            line,
+            count: inserted,
+            lines: removed,
+        });
+        self.modified = true;
+        self.swap_pending += 1;
+    }
+
+    // Record a change for undo. Changes are normally grouped by the command
+    // that made them, a buffer a command only reached part way through, e.g.
+    // one it opened, starts its step at its first change.
+    pub fn record(&mut self, change: Change) {
+        if !self.history.in_step() {
+            self.history.begin(self.curr_line);
+        }
+        self.history.record(change);
+    }
+
+    // All changes to lines go through splice so bookkeeping that refers to line
+    // numbers stays in sync. Replaced lines keep their identity, lines beyond
+    // the number of inserted lines are treated as deleted.
+    fn splice(&mut self, line: u32, count: usize, lines: Text) -> Text {
+        let start = line as usize;
+        let inserted = lines.len() as u32;
+        if self.swap_file.is_some() {
+            self.swap_journal.record(line, count, &lines);
+        }
+        let removed = self.contents.splice(start, count, lines);
+
+        let count = count as u32;
+        let shift = |l: u32| {
+            if l < line + inserted.min(count) {
+                Some(l)
+            } else if l < line + count {
+                None
+            } else {
+                Some(l + inserted - count)
+            }
+        };
+
+        if let Some(global_lines) = &mut self.global_lines {
+            *global_lines = global_lines.iter().filter_map(|l| shift(*l)).collect();
+        }
+
+        self.marks = self
+            .marks
+            .iter()
+            .filter_map(|(name, l)| shift(*l).map(|l| (*name, l)))
+            .collect();
+
+        removed
+    }
+
+    // Change how the buffer is saved
+    pub fn set_format(&mut self, format: FileFormat) {
+        if format == self.format {
+            return;
+        }
+
+        let old = std::mem::replace(&mut self.format, format);
+        self.record(Change::Format(old));
+        self.modified = true;
+        self.swap_pending += 1;
+    }
+
+    // Change where the buffer is saved. The new file doesn't have the buffer's
+    // contents yet, so the buffer counts as modified.
+    pub fn set_filename(&mut self, filename: PathBuf) {
+        let old = self.filename.replace(filename);
+        self.record(Change::File {
+            filename: old,
+            format: self.format,
+        });
+        self.modified = true;
+        self.swap_pending += 1;
+    }
+
+    // Replace the buffer with a different file. Undo can't go back to the
+    // previous file, so its contents aren't kept.
+    pub fn replace_file(&mut self, filename: PathBuf, format: FileFormat, contents: Text) {
+        self.history.clear();
+        self.filename = Some(filename);
+        self.format = format;
+        self.contents = contents;
+        self.swap_pending += 1;
+        self.swap_journal.reset();
+    }
+
+    // Returns false if there was nothing to undo.
+    pub fn undo(&mut self) -> bool {
+        match self.history.pop_undo() {
+            Some(step) => {
+                let step = self.revert(step);
+                self.history.push_redo(step);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Returns false if there was nothing to redo.
+    pub fn redo(&mut self) -> bool {
+        match self.history.pop_redo() {
+            Some(step) => {
+                let step = self.revert(step);
+                self.history.push_undo(step);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Revert all changes in a step. Returns the step that will reapply them.
+    fn revert(&mut self, step: Step) -> Step {
+        // Changes are reverted newest first, which leaves the inverse changes
+        // ordered so that reverting them the same way reapplies the step
+        let changes: Vec<Change> = step
+            .changes
+            .into_iter()
+            .rev()
+            .map(|change| match change {
+                Change::Splice { line, count, lines } => {
+                    let inserted = lines.len();
+                    Change::Splice {
+                        line,
+                        count: inserted,
+                        lines: self.splice(line, count, lines),
+                    }
+                }
+                Change::File { filename, format } => Change::File {
+                    filename: std::mem::replace(&mut self.filename, filename),
+                    format: std::mem::replace(&mut self.format, format),
+                },
+                Change::Format(format) => {
+                    Change::Format(std::mem::replace(&mut self.format, format))
+                }
+            })
+            .collect();
+
+        self.set_current_line(step.line_before);
+        self.modified = true;
+        self.swap_pending += 1;
+
+        Step {
+            changes,
+            line_before: step.line_after,
+            line_after: step.line_before,
+        }
+    }
+}
+
+// Read a file and split it into lines. The encoding is detected unless one is
+// given.
+pub fn read_file(
+    path: &Path,
+    encoding: Option<&'static Encoding>,
+) -> Result<(Text, FileFormat, Stamp), Error> {
+    let file = File::open(path)?;
+    // Taken before reading so changes made while reading aren't missed
+    let meta = file.metadata()?;
+
+    let mut reader = BufReader::with_capacity(READ_BUF_SIZE, Crc::new(file));
+    let (contents, format) = read_compressed(&mut reader, encoding)?;
+    let stamp = Stamp::new(path, &meta, reader.get_ref().sum());
+    Ok((contents, format, stamp))
+}
+
+pub fn read_stdin(encoding: Option<&'static Encoding>) -> Result<(Text, FileFormat), Error> {
+    read_compressed(io::stdin().lock(), encoding)
+}
+
+// Read text that may be compressed, going by its first bytes
+fn read_compressed<R: BufRead>(
+    mut reader: R,
+    encoding: Option<&'static Encoding>,
+) -> Result<(Text, FileFormat), Error> {
+    let compression = Compression::detect(reader.fill_buf()?);
+    let (contents, mut format) = FileFormat::read(compression.reader(reader)?, encoding)?;
+    format.compression = compression;
+    Ok((contents, format))
+}
@@ -0,0 +1,129 @@
+// Compressed files are decompressed when they're read and compressed again
+// with the same format when they're written.
+
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+
+use flate2::bufread::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use xz2::bufread::XzDecoder;
+use xz2::write::XzEncoder;
+
+const GZIP_MAGIC: &[u8] = &[0x1F, 0x8B];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xB5, 0x2F, 0xFD];
+const XZ_MAGIC: &[u8] = &[0xFD, b'7', b'z', b'X', b'Z', 0x00];
+
+// Levels used when a file's level isn't known
+const GZIP_LEVEL: u32 = 6;
+const ZSTD_LEVEL: i32 = 3;
+const XZ_LEVEL: u32 = 6;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Compression {
+    None,
+    Gzip(u32),
+    Zstd(i32),
+    Xz(u32),
+}
+
+impl Compression {
+    // Detect the compression from the start of a file. Only gzip records
+    // anything about the level used, and only whether it was the best or
+    // fastest.
+    pub fn detect(header: &[u8]) -> Compression {
+        if header.starts_with(GZIP_MAGIC) {
+            match header.get(8) {
+                Some(2) => Compression::Gzip(9),
+                Some(4) => Compression::Gzip(1),
+                _ => Compression::Gzip(GZIP_LEVEL),
+            }
+        } else if header.starts_with(ZSTD_MAGIC) {
+            Compression::Zstd(ZSTD_LEVEL)
+        } else if header.starts_with(XZ_MAGIC) {
+            Compression::Xz(XZ_LEVEL)
+        } else {
+            Compression::None
+        }
+    }
+
+    // Compression for a new file, going by its extension
+    pub fn for_path(path: &Path) -> Compression {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some(ext) => Compression::parse(ext, None).unwrap_or(Compression::None),
+            None => Compression::None,
+        }
+    }
+
+    pub fn parse(name: &str, level: Option<&str>) -> Option<Compression> {
+        let level = match level {
+            Some(l) => Some(l.parse::<i32>().ok()?),
+            None => None,
+        };
+
+        let compression = match name.to_ascii_lowercase().as_str() {
+            "none" if level.is_none() => Compression::None,
+            "gz" | "gzip" => Compression::Gzip(level.unwrap_or(GZIP_LEVEL as i32) as u32),
+            "zst" | "zstd" => Compression::Zstd(level.unwrap_or(ZSTD_LEVEL)),
+            "xz" => Compression::Xz(level.unwrap_or(XZ_LEVEL as i32) as u32),
+            _ => return None,
+        };
+
+        let valid = match compression {
+            Compression::None => true,
+            Compression::Gzip(l) | Compression::Xz(l) => l <= 9,
+            Compression::Zstd(l) => zstd::compression_level_range().contains(&l),
+        };
+        valid.then_some(compression)
+    }
+
+    // Wrap a reader so it reads the decompressed contents
+    pub fn reader<'a, R: BufRead + 'a>(self, reader: R) -> io::Result<Box<dyn BufRead + 'a>> {
+        Ok(match self {
+            Compression::None => Box::new(reader),
+            Compression::Gzip(_) => Box::new(BufReader::new(MultiGzDecoder::new(reader))),
+            Compression::Zstd(_) => Box::new(BufReader::new(zstd::Decoder::with_buffer(reader)?)),
+            Compression::Xz(_) => Box::new(BufReader::new(XzDecoder::new_multi_decoder(reader))),
+        })
+    }
+
+    // Call write with a writer that compresses what's written to w
+    pub fn write<W, F>(self, w: W, write: F) -> io::Result<()>
+    where
+        W: Write,
+        F: FnOnce(&mut dyn Write) -> io::Result<()>,
+    {
+        match self {
+            Compression::None => {
+                let mut w = w;
+                write(&mut w)
+            }
+            Compression::Gzip(level) => {
+                let mut encoder = GzEncoder::new(w, flate2::Compression::new(level));
+                write(&mut encoder)?;
+                encoder.finish().map(|_| ())
+            }
+            Compression::Zstd(level) => {
+                let mut encoder = zstd::Encoder::new(w, level)?;
+                write(&mut encoder)?;
+                encoder.finish().map(|_| ())
+            }
+            Compression::Xz(level) => {
+                let mut encoder = XzEncoder::new(w, level);
+                write(&mut encoder)?;
+                encoder.finish().map(|_| ())
+            }
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Compression::None => f.write_str("none"),
+            Compression::Gzip(level) => write!(f, "gzip level {}", level),
+            Compression::Zstd(level) => write!(f, "zstd level {}", level),
+            Compression::Xz(level) => write!(f, "xz level {}", level),
+        }
+    }
+}
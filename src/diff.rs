@@ -0,0 +1,326 @@
+// Line diffs using Myers' algorithm in linear space, printed in unified diff
+// format.
+
+use crate::line::LineRef;
+use crate::text::Text;
+
+const CONTEXT_LINES: usize = 3;
+
+#[derive(Clone, Copy)]
+enum Edit {
+    Equal(usize),
+    Delete(usize),
+    Insert(usize),
+}
+
+// Print the differences between old and new. Returns false if they're equal.
+pub fn print_diff(old: &Text, new: &Text, old_name: &str, new_name: &str) -> bool {
+    let old: Vec<LineRef> = old.iter().collect();
+    let new: Vec<LineRef> = new.iter().collect();
+    let edits = diff(&old, &new);
+    if edits.iter().all(|e| matches!(e, Edit::Equal(..))) {
+        return false;
+    }
+
+    println!("--- {}", old_name);
+    println!("+++ {}", new_name);
+
+    // Position in old and new before each edit
+    let mut positions = Vec::with_capacity(edits.len() + 1);
+    let (mut a, mut b) = (0, 0);
+    for edit in &edits {
+        positions.push((a, b));
+        match edit {
+            Edit::Equal(..) => {
+                a += 1;
+                b += 1;
+            }
+            Edit::Delete(_) => a += 1,
+            Edit::Insert(_) => b += 1,
+        }
+    }
+    positions.push((a, b));
+
+    for (start, end) in hunks(&edits) {
+        let (a_start, b_start) = positions[start];
+        let (a_end, b_end) = positions[end];
+        println!(
+            "@@ -{},{} +{},{} @@",
+            a_start + 1,
+            a_end - a_start,
+            b_start + 1,
+            b_end - b_start
+        );
+
+        for edit in &edits[start..end] {
+            match *edit {
+                Edit::Equal(i) => println!(" {}", old[i]),
+                Edit::Delete(i) => println!("-{}", old[i]),
+                Edit::Insert(j) => println!("+{}", new[j]),
+            }
+        }
+    }
+
+    true
+}
+
+// Group changes into ranges of edits with surrounding context.
+fn hunks(edits: &[Edit]) -> Vec<(usize, usize)> {
+    let mut hunks: Vec<(usize, usize)> = Vec::new();
+
+    for (i, edit) in edits.iter().enumerate() {
+        if matches!(edit, Edit::Equal(..)) {
+            continue;
+        }
+
+        let start = i.saturating_sub(CONTEXT_LINES);
+        let end = (i + 1 + CONTEXT_LINES).min(edits.len());
+
+        match hunks.last_mut() {
+            Some(last) if start <= last.1 => last.1 = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+
+    hunks
+}
+
+fn diff<T: PartialEq>(a: &[T], b: &[T]) -> Vec<Edit> {
+    let mut edits = Vec::new();
+    diff_range(a, b, 0, 0, &mut edits);
+    edits
+}
+
+// Add the edits turning a into b, where a and b start at a_start and b_start
+// in the whole texts. The middle of the diff is split in two at a point on the
+// shortest edit path and each half diffed on its own, so only one row of
+// Myers' algorithm is ever kept rather than one for every edit.
+fn diff_range<T: PartialEq>(
+    a: &[T],
+    b: &[T],
+    a_start: usize,
+    b_start: usize,
+    edits: &mut Vec<Edit>,
+) {
+    // Most diffs are small changes to large files, so only run the diff on the
+    // part between the common prefix and suffix
+    let prefix = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+    let suffix = a[prefix..]
+        .iter()
+        .rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+
+    edits.extend((0..prefix).map(|i| Edit::Equal(a_start + i)));
+
+    let (a_mid, b_mid) = (&a[prefix..a.len() - suffix], &b[prefix..b.len() - suffix]);
+    let (a_mid_start, b_mid_start) = (a_start + prefix, b_start + prefix);
+    match middle(a_mid, b_mid) {
+        Some((x, y)) => {
+            diff_range(&a_mid[..x], &b_mid[..y], a_mid_start, b_mid_start, edits);
+            diff_range(
+                &a_mid[x..],
+                &b_mid[y..],
+                a_mid_start + x,
+                b_mid_start + y,
+                edits,
+            );
+        }
+        None => {
+            edits.extend((0..a_mid.len()).map(|i| Edit::Delete(a_mid_start + i)));
+            edits.extend((0..b_mid.len()).map(|j| Edit::Insert(b_mid_start + j)));
+        }
+    }
+
+    let a_end = a_start + a.len() - suffix;
+    edits.extend((0..suffix).map(|i| Edit::Equal(a_end + i)));
+}
+
+// Find a point in the middle of the shortest edit path from a to b by running
+// Myers' algorithm forwards from the start and backwards from the end until
+// the paths meet. Returns None if a and b have nothing in common. a and b
+// mustn't start or end with the same line, so the point splits the diff into
+// two smaller ones.
+fn middle<T: PartialEq>(a: &[T], b: &[T]) -> Option<(usize, usize)> {
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    if n == 0 || m == 0 {
+        return None;
+    }
+
+    // Furthest x reached on each diagonal k = x - y, going forwards and
+    // backwards, -1 if the diagonal hasn't been reached
+    let max_d = (n + m + 1) / 2;
+    let offset = max_d;
+    let mut forward = vec![-1isize; 2 * max_d as usize + 2];
+    let mut backward = forward.clone();
+    forward[offset as usize + 1] = 0;
+    backward[offset as usize + 1] = 0;
+
+    // With an odd difference in lengths the paths meet going forwards,
+    // otherwise backwards
+    let delta = n - m;
+    let odd = delta % 2 != 0;
+
+    // Diagonals that ran off the edit graph are skipped from then on
+    let (mut k1_start, mut k1_end, mut k2_start, mut k2_end) = (0, 0, 0, 0);
+
+    for d in 0..max_d {
+        let mut k1 = -d + k1_start;
+        while k1 <= d - k1_end {
+            let i = (offset + k1) as usize;
+            let mut x1 = if k1 == -d || (k1 != d && forward[i - 1] < forward[i + 1]) {
+                forward[i + 1]
+            } else {
+                forward[i - 1] + 1
+            };
+            let mut y1 = x1 - k1;
+            while x1 < n && y1 < m && a[x1 as usize] == b[y1 as usize] {
+                x1 += 1;
+                y1 += 1;
+            }
+            forward[i] = x1;
+
+            if x1 > n {
+                k1_end += 2;
+            } else if y1 > m {
+                k1_start += 2;
+            } else if odd {
+                let j = offset + delta - k1;
+                if j >= 0 && j < backward.len() as isize && backward[j as usize] != -1 {
+                    let x2 = n - backward[j as usize];
+                    if x1 >= x2 {
+                        return Some((x1 as usize, y1 as usize));
+                    }
+                }
+            }
+            k1 += 2;
+        }
+
+        let mut k2 = -d + k2_start;
+        while k2 <= d - k2_end {
+            let i = (offset + k2) as usize;
+            let mut x2 = if k2 == -d || (k2 != d && backward[i - 1] < backward[i + 1]) {
+                backward[i + 1]
+            } else {
+                backward[i - 1] + 1
+            };
+            let mut y2 = x2 - k2;
+            while x2 < n && y2 < m && a[(n - x2 - 1) as usize] == b[(m - y2 - 1) as usize] {
+                x2 += 1;
+                y2 += 1;
+            }
+            backward[i] = x2;
+
+            if x2 > n {
+                k2_end += 2;
+            } else if y2 > m {
+                k2_start += 2;
+            } else if !odd {
+                let j = offset + delta - k2;
+                if j >= 0 && j < forward.len() as isize && forward[j as usize] != -1 {
+                    let x1 = forward[j as usize];
+                    let y1 = offset + x1 - j;
+                    if x1 >= n - x2 {
+                        return Some((x1 as usize, y1 as usize));
+                    }
+                }
+            }
+            k2 += 2;
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Apply edits to a, checking they describe a valid diff to b
+    fn check(a: &str, b: &str) -> usize {
+        let (a, b): (Vec<char>, Vec<char>) = (a.chars().collect(), b.chars().collect());
+        let edits = diff(&a, &b);
+
+        let (mut i, mut j, mut changes) = (0, 0, 0);
+        for edit in edits {
+            match edit {
+                Edit::Equal(x) => {
+                    assert_eq!(x, i);
+                    assert_eq!(a[i], b[j]);
+                    i += 1;
+                    j += 1;
+                }
+                Edit::Delete(x) => {
+                    assert_eq!(x, i);
+                    i += 1;
+                    changes += 1;
+                }
+                Edit::Insert(y) => {
+                    assert_eq!(y, j);
+                    j += 1;
+                    changes += 1;
+                }
+            }
+        }
+        assert_eq!((i, j), (a.len(), b.len()));
+        changes
+    }
+
+    #[test]
+    fn shortest_edits() {
+        assert_eq!(check("", ""), 0);
+        assert_eq!(check("abc", "abc"), 0);
+        assert_eq!(check("", "abc"), 3);
+        assert_eq!(check("abc", ""), 3);
+        assert_eq!(check("abc", "xyz"), 6);
+        assert_eq!(check("abcabba", "cbabac"), 5);
+        assert_eq!(check("abcdefgh", "axcyefzh"), 6);
+        assert_eq!(check("ab", "ba"), 2);
+        assert_eq!(check("abcd", "dcba"), 6);
+        assert_eq!(check("xaxbxcx", "abc"), 4);
+    }
+
+    // Every pair of short strings of a and b gets a shortest diff
+    #[test]
+    fn matches_longest_common_subsequence() {
+        let strings: Vec<String> = (0..6)
+            .flat_map(|len| {
+                (0..1 << len).map(move |bits: u32| {
+                    (0..len)
+                        .map(|i| if bits & 1 << i != 0 { 'a' } else { 'b' })
+                        .collect()
+                })
+            })
+            .collect();
+
+        for a in &strings {
+            for b in &strings {
+                let (x, y): (Vec<char>, Vec<char>) = (a.chars().collect(), b.chars().collect());
+                let mut lcs = vec![vec![0; y.len() + 1]; x.len() + 1];
+                for i in 0..x.len() {
+                    for j in 0..y.len() {
+                        lcs[i + 1][j + 1] = if x[i] == y[j] {
+                            lcs[i][j] + 1
+                        } else {
+                            lcs[i][j + 1].max(lcs[i + 1][j])
+                        };
+                    }
+                }
+                let shortest = x.len() + y.len() - 2 * lcs[x.len()][y.len()];
+                assert_eq!(check(a, b), shortest, "{:?} {:?}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn long_diff() {
+        let a: String = (0..2000)
+            .map(|i| if i % 7 == 0 { 'x' } else { 'a' })
+            .collect();
+        let b: String = (0..2000)
+            .map(|i| if i % 5 == 0 { 'x' } else { 'a' })
+            .collect();
+        check(&a, &b);
+    }
+}
@@ -1,109 +1,414 @@
-use std::fs::{File, OpenOptions};
-use std::io::Error;
-use std::io::{prelude::*, Write};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Error, Write};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
-use crate::terminal::Terminal;
+use encoding_rs::{Encoding, UTF_8};
+
+use crate::address::{self, Range};
+use crate::buffer::{self, Buffer};
+use crate::compress::Compression;
+use crate::diff;
+use crate::file::{self, Crc, Stamp};
+use crate::format::{FileFormat, LineEnding};
+use crate::line::{Line, LineRef};
+use crate::lock;
+use crate::search::{split_delimited, Search};
+use crate::settings::Settings;
+use crate::shell;
+use crate::substitute::Substitution;
+use crate::swap;
+use crate::terminal::{self, Terminal};
+use crate::text::Text;
+use crate::undo::Change;
 
 pub struct Editor {
-    filename: Option<PathBuf>,
-    newline_seq: &'static str,
     terminal: Terminal,
-    contents: Vec<String>,
-    curr_line: u32,
+    buffers: Vec<Buffer>,
+    current: usize, // Index of the current buffer
+    settings: Settings,
+    backed_up: HashSet<PathBuf>, // Files already backed up this session
+    last_search: Option<Search>,
+    registers: HashMap<char, Text>,
+    last_shell_cmd: Option<String>,
+    stdout: Option<File>, // Where w - writes when stdout isn't the terminal
 }
 
 impl Editor {
-    pub fn new<P: AsRef<Path>>(path: P) -> Result<Editor, Error> {
-        if !path.as_ref().exists() {
-            let mut editor = Self::new_empty();
-            editor.filename = Some(path.as_ref().to_owned());
-            return Ok(editor);
+    pub fn new_empty() -> Editor {
+        Editor {
+            terminal: Terminal::new(),
+            buffers: vec![Buffer::new()],
+            current: 0,
+            settings: Settings::new(),
+            backed_up: HashSet::new(),
+            last_search: None,
+            registers: HashMap::new(),
+            last_shell_cmd: None,
+            stdout: None,
         }
+    }
 
-        let mut the_file = OpenOptions::new().read(true).write(true).open(&path)?;
+    // Load a file into a new buffer without switching to it. The empty buffer
+    // the editor starts with is replaced by the first file loaded.
+    // Returns the buffer's index.
+    pub fn add_buffer<P: AsRef<Path>>(&mut self, path: P) -> Result<usize, Error> {
+        let buffer = Buffer::load(path, self.settings.encoding)?;
+        let idx = self.push_buffer(buffer);
 
-        let mut file_contents = String::new();
-        the_file.read_to_string(&mut file_contents)?;
-        let newline_char = if file_contents.contains('\r') {
-            "\r\n"
-        } else {
-            "\n"
-        };
+        self.lock_buffer(idx);
+        if !self.buffers[idx].read_only {
+            self.check_swap(idx);
+        }
+        Ok(idx)
+    }
 
-        Ok(Editor {
-            filename: Some(path.as_ref().to_owned()),
-            newline_seq: newline_char,
-            terminal: Terminal::new(),
-            contents: file_contents
-                .split(newline_char)
-                .map(|s| s.to_owned())
-                .collect(),
-            curr_line: 0,
-        })
+    // Keep stdout for w - and show the editor on the terminal instead, when
+    // stdout is redirected.
+    pub fn use_tty_for_output(&mut self) -> Result<(), Error> {
+        self.stdout = terminal::use_tty_for_output()?;
+        Ok(())
     }
 
-    pub fn new_empty() -> Editor {
-        Editor {
-            filename: None,
-            newline_seq: "\n",
-            terminal: Terminal::new(),
-            contents: Vec::with_capacity(10),
-            curr_line: 0,
+    // Load piped input into a new buffer without a file, then take keys from
+    // the terminal since stdin has been used up.
+    pub fn add_stdin_buffer(&mut self) -> Result<usize, Error> {
+        let (contents, format) = buffer::read_stdin(self.settings.encoding)?;
+
+        let mut buffer = Buffer::new();
+        buffer.format = format;
+        buffer.contents = contents;
+        let idx = self.push_buffer(buffer);
+
+        terminal::use_tty_for_input()?;
+        Ok(idx)
+    }
+
+    // Add a loaded buffer, replacing the empty buffer the editor starts with.
+    // Returns the buffer's index.
+    fn push_buffer(&mut self, mut buffer: Buffer) -> usize {
+        buffer.history.set_depth(self.settings.undo_depth);
+
+        let first = &self.buffers[0];
+        if self.buffers.len() == 1
+            && first.filename.is_none()
+            && first.contents.is_empty()
+            && !first.modified
+        {
+            self.buffers[0] = buffer;
+        } else {
+            self.buffers.push(buffer);
+        }
+        self.buffers.len() - 1
+    }
+
+    pub fn set_option(&mut self, name: &str, value: &str) -> Result<(), String> {
+        self.settings.set(name, value)?;
+
+        for buffer in &mut self.buffers {
+            buffer.history.set_depth(self.settings.undo_depth);
         }
+        Ok(())
+    }
+
+    fn buf(&self) -> &Buffer {
+        &self.buffers[self.current]
+    }
+
+    fn buf_mut(&mut self) -> &mut Buffer {
+        &mut self.buffers[self.current]
     }
 
     pub fn run(&mut self) {
         loop {
             let cmd_line = self.read_cmd();
-            let cmd: Vec<&str> = cmd_line.split_whitespace().collect();
-            if cmd.is_empty() {
-                continue;
-            }
-
-            match cmd[0] {
-                "?" => self.print_help(),
-                "c" => self.context_cmd(&cmd[1..]),
-                "d" => self.delete_line(),
-                "e" => self.edit_mode(),
-                "f" => self.find_next(&cmd[1..]),
-                "F" => self.find_prev(&cmd[1..]),
-                "i" => self.insert_down(),
-                "I" => self.insert_up(),
-                "m" => self.metadata(),
-                "q" => return,
-                "p" => self.print_line(&cmd[1..]),
-                "w" => self.save(&cmd[1..]),
-                "W" => {
-                    self.save(&cmd[1..]);
-                    return;
+            if !self.execute(&cmd_line) {
+                return;
+            }
+        }
+    }
+
+    // Run a command line typed at the prompt or given with -c. Returns false,
+    // once swap files and locks are cleaned up, if the editor should quit.
+    pub fn execute(&mut self, cmd_line: &str) -> bool {
+        // Everything a command line changes is undone as one step in each
+        // buffer it changes, including ones it switches to
+        for buffer in &mut self.buffers {
+            buffer.history.begin(buffer.curr_line);
+        }
+        let keep_running = self.run_command(cmd_line);
+        for buffer in &mut self.buffers {
+            buffer.history.commit(buffer.curr_line);
+        }
+
+        if !keep_running {
+            self.shutdown();
+            return false;
+        }
+
+        self.update_swap_files();
+        true
+    }
+
+    // Remove swap files and release locks before poe exits.
+    pub fn shutdown(&mut self) {
+        for buffer in &mut self.buffers {
+            if let Some(swap_file) = buffer.swap_file.take() {
+                swap::remove(&swap_file);
+            }
+            if let Some(lock_file) = buffer.lock.take() {
+                lock::release(&lock_file);
+            }
+        }
+    }
+
+    // Move to where a file opened from the command line should start: +N goes
+    // to line N, + to the last line and +/RE to the first line matching RE.
+    pub fn jump(&mut self, idx: usize, spec: &str) {
+        let current = std::mem::replace(&mut self.current, idx);
+
+        if spec.starts_with('/') {
+            match Search::parse_regex(spec) {
+                Ok(search) => {
+                    self.last_search = Some(search);
+                    self.find_from(0);
+                }
+                Err(e) => println!("{}", e),
+            }
+        } else if spec.is_empty() {
+            let last = self.buf().contents.len();
+            self.goto_line(Some((last, last)));
+        } else {
+            match spec.parse::<usize>() {
+                Ok(line) => self.goto_line(Some((line, line))),
+                Err(_) => println!("Invalid line number: {}", spec),
+            }
+        }
+
+        self.current = current;
+    }
+
+    // Execute a single command line. Returns false when the editor should quit.
+    fn run_command(&mut self, cmd_line: &str) -> bool {
+        if cmd_line.trim().is_empty() {
+            return true;
+        }
+
+        let (range, rest) = match address::parse_range(cmd_line, self) {
+            Ok(r) => r,
+            Err(e) => {
+                println!("{}", e);
+                return true;
+            }
+        };
+
+        let mut chars = rest.trim_start().chars();
+        let cmd = match chars.next() {
+            Some(c) => c,
+            None => {
+                self.goto_line(range);
+                return true;
+            }
+        };
+        let arg_str = chars.as_str();
+        let args: Vec<&str> = arg_str.split_whitespace().collect();
+
+        if !valid_args(cmd, arg_str) {
+            println!("Unknown command: {}", rest.trim());
+            return true;
+        }
+
+        match cmd {
+            '?' => self.print_help(),
+            '!' => self.shell(range, arg_str),
+            'b' => self.buffer_cmd(arg_str),
+            'B' => self.close_buffer(arg_str),
+            'c' => {
+                if let Some((start, end)) = self.line_range(range) {
+                    self.context_cmd(start, end, &args);
+                }
+            }
+            'd' => {
+                if let Some(reg) = parse_register(&args) {
+                    if let Some((start, end)) = self.line_range(range) {
+                        self.delete_lines(start, end, reg);
+                    }
+                }
+            }
+            'g' | 'v' => return self.global(range, arg_str, cmd == 'v'),
+            'k' => {
+                if let Some((_, end)) = self.line_range(range) {
+                    self.mark_cmd(end, arg_str);
+                }
+            }
+            'E' => self.encoding_cmd(&args),
+            'L' => self.line_ending_cmd(&args),
+            'Z' => self.compression_cmd(&args),
+            // m without an address or destination prints metadata
+            'm' if range.is_none() && args.is_empty() => self.metadata(),
+            'm' | 't' => {
+                if let Some((start, end)) = self.line_range(range) {
+                    if let Some(dest) = self.destination(arg_str) {
+                        if cmd == 'm' {
+                            self.move_lines(start, end, dest);
+                        } else {
+                            self.copy_lines(start, end, dest);
+                        }
+                    }
+                }
+            }
+            'j' => {
+                // Without a range, join the current and next line
+                let range = match range {
+                    None if !self.buf().contents.is_empty() => {
+                        let line = self.buf().curr_line as usize + 1;
+                        Some((line, (line + 1).min(self.buf().contents.len())))
+                    }
+                    r => r,
+                };
+
+                if let Some((start, end)) = self.line_range(range) {
+                    self.join_lines(start, end, arg_str);
                 }
-                "o" => self.open(&cmd[1..]),
-                _ => {
-                    if let Ok(line) = cmd[0].parse::<u32>() {
-                        self.set_current_line(if line == 0 { 0 } else { line - 1 });
+            }
+            'x' | 'X' => {
+                if let Some(reg) = parse_register(&args) {
+                    self.put(range, reg, cmd == 'X');
+                }
+            }
+            'y' => {
+                if let Some(reg) = parse_register(&args) {
+                    if let Some((start, end)) = self.line_range(range) {
+                        self.yank(start, end, reg);
+                    }
+                }
+            }
+            'S' => self.settings_cmd(&args),
+            's' => {
+                if let Some((start, end)) = self.line_range(range) {
+                    self.substitute(start, end, arg_str);
+                }
+            }
+            'q' => return !self.can_quit(),
+            'Q' => return false,
+            'u' => {
+                if !self.buf_mut().undo() {
+                    println!("Nothing to undo");
+                }
+            }
+            'U' => {
+                if !self.buf_mut().redo() {
+                    println!("Nothing to redo");
+                }
+            }
+            'p' => self.print_line(range, &args),
+            'r' => match arg_str.trim_start().strip_prefix('!') {
+                Some(shell_cmd) => self.read_command(range, shell_cmd),
+                None => self.read_file(range, &args),
+            },
+            'n' => self.filename_cmd(&args),
+            // wq is ed's spelling of W
+            'w' if arg_str.starts_with('q') => {
+                self.save(range, &arg_str[1..]);
+                return !self.can_quit();
+            }
+            'w' => self.save(range, arg_str),
+            'W' => {
+                self.save(range, arg_str);
+                return !self.can_quit();
+            }
+            'o' => match arg_str.strip_prefix('!') {
+                Some(rest) => self.open(&rest.split_whitespace().collect::<Vec<_>>(), true),
+                None => self.open(&args, false),
+            },
+            'O' => self.open_buffer(&args),
+            'e' | 'f' | 'F' | 'i' | 'I' => {
+                if range.is_some() {
+                    match self.line_range(range) {
+                        Some((_, end)) => self.buf_mut().curr_line = end,
+                        None => return true,
                     }
-                    self.print_context(self.curr_line, 2);
+                }
+
+                match cmd {
+                    'e' if arg_str.starts_with('!') => self.reload(),
+                    'e' => self.edit_mode(),
+                    'f' => self.find_next(arg_str),
+                    'F' => self.find_prev(arg_str),
+                    'i' => self.insert_down(),
+                    _ => self.insert_up(),
                 }
             }
+            _ => println!("Unknown command: {}", cmd),
         }
+
+        true
+    }
+
+    // Convert an address range into 0-based line indexes, defaulting to the
+    // current line. Prints an error and returns None if the range doesn't
+    // refer to lines in the buffer.
+    fn line_range(&self, range: Option<Range>) -> Option<(u32, u32)> {
+        if self.buf().contents.is_empty() {
+            println!("Buffer is empty");
+            return None;
+        }
+
+        match range {
+            None => Some((self.buf().curr_line, self.buf().curr_line)),
+            Some((0, _)) => {
+                println!("Invalid address");
+                None
+            }
+            Some((start, end)) => Some((start as u32 - 1, end as u32 - 1)),
+        }
+    }
+
+    fn goto_line(&mut self, range: Option<Range>) {
+        if self.buf().contents.is_empty() {
+            println!("Buffer is empty");
+            return;
+        }
+
+        if let Some((_, line)) = range {
+            self.buf_mut()
+                .set_current_line(if line == 0 { 0 } else { line as u32 - 1 });
+        }
+        self.print_context(self.buf().curr_line, self.buf().curr_line, 2);
     }
 
-    fn set_current_line(&mut self, line: u32) {
-        self.curr_line = line;
+    // Returns true if no buffer has unsaved changes, otherwise lists them.
+    fn can_quit(&self) -> bool {
+        let unsaved: Vec<String> = self
+            .buffers
+            .iter()
+            .filter(|b| b.modified)
+            .map(|b| b.name())
+            .collect();
 
-        if self.curr_line >= self.contents.len() as u32 {
-            self.curr_line = self.contents.len() as u32 - 1;
+        if unsaved.is_empty() {
+            return true;
         }
+
+        println!(
+            "Unsaved changes in {}, use Q to quit anyway",
+            unsaved.join(", ")
+        );
+        false
     }
 
-    fn open(&mut self, args: &[&str]) {
+    fn open(&mut self, args: &[&str], force: bool) {
         if args.is_empty() {
             println!("Invalid file name");
             return;
         }
 
+        if self.buf().modified && !force {
+            println!("Buffer has unsaved changes, use o! to open anyway");
+            return;
+        }
+
         let path = match PathBuf::from_str(args[0]) {
             Ok(p) => p,
             _ => {
@@ -117,74 +422,159 @@ impl Editor {
             return;
         }
 
-        let mut the_file = match OpenOptions::new().read(true).write(true).open(&path) {
-            Ok(f) => f,
-            _ => {
-                println!("Invalid file name");
-                return;
+        if self.load_file(path) {
+            self.lock_buffer(self.current);
+            if !self.buf().read_only {
+                self.check_swap(self.current);
             }
-        };
+        }
+    }
 
-        let mut file_contents = String::new();
-        if the_file.read_to_string(&mut file_contents).is_err() {
-            println!("Error reading file");
-            return;
+    // Re-read the buffer's file, discarding changes. Like opening a file, this
+    // can be undone.
+    fn reload(&mut self) {
+        match self.buf().filename.clone() {
+            Some(path) if path.exists() => {
+                if self.load_file(path) {
+                    println!("Reloaded {}", self.buf().name());
+                }
+            }
+            Some(_) => println!("File not found"),
+            None => println!("No filename"),
         }
-        let newline_char = if file_contents.contains('\n') {
-            "\n"
-        } else {
-            "\r\n"
+    }
+
+    // Replace the current buffer's contents with a file. Reloading the
+    // buffer's own file can be undone, opening another one clears the undo
+    // history. Returns false if the file couldn't be read.
+    fn load_file(&mut self, path: PathBuf) -> bool {
+        let (contents, format, stamp) = match buffer::read_file(&path, self.settings.encoding) {
+            Ok(r) => r,
+            Err(e) => {
+                println!("Error reading {}: {}", path.display(), e);
+                return false;
+            }
         };
 
-        self.filename = Some(path);
-        self.newline_seq = newline_char;
-        self.contents = file_contents
-            .split(newline_char)
-            .map(|s| s.to_owned())
-            .collect();
-        self.curr_line = 0;
+        let buffer = self.buf_mut();
+        if buffer.filename.as_ref() == Some(&path) {
+            let change = Change::File {
+                filename: buffer.filename.clone(),
+                format: std::mem::replace(&mut buffer.format, format),
+            };
+            buffer.record(change);
+            buffer.edit(0, buffer.contents.len(), contents);
+        } else {
+            buffer.replace_file(path, format, contents);
+        }
+        buffer.curr_line = 0;
+        buffer.modified = false;
+        buffer.stamp = Some(stamp);
+        buffer.marks.clear();
+
+        if let Some(lines) = &mut buffer.global_lines {
+            lines.clear();
+        }
+        true
     }
 
     fn read_cmd(&mut self) -> String {
+        let buffer = self.buf();
+        let modified = if buffer.modified { "*" } else { "" };
         self.terminal
-            .readline(&format!("{} > ", self.curr_line + 1))
+            .readline(&format!("{}{} > ", buffer.curr_line + 1, modified))
     }
 
     fn print_help(&mut self) {
-        println!("          NUM - Set current line and print 2 lines of context");
-        println!("            ? - Print this help");
-        println!("      c [NUM] - Print context, defaults to 2 lines");
-        println!("            d - Delete current line");
-        println!("            e - Edit current line");
-        println!("     f [TEXT] - Find text below current line");
-        println!("     F [TEXT] - Find text above current line");
-        println!("            i - Insert new line below current line");
-        println!("            I - Insert new line above current line");
-        println!("            m - Print editor data");
-        println!("            q - Quit");
         println!(
-            "p [NUM] [CON] - Print current line or line NUM with optional CON lines of context"
+            "Commands may be prefixed by an address or range: N, ., $, 'x, +N, -N, N,M, %, N;M"
         );
-        println!(" w [FILENAME] - Write file to FILENAME or opened file location");
-        println!(" W [FILENAME] - Write file to FILENAME or opened file location and quit");
-        println!(" o [FILENAME] - Open FILENAME");
+        println!("          ADDR - Set current line and print 2 lines of context");
+        println!("             ? - Print this help");
+        println!("       c [NUM] - Print context around range, defaults to 2 lines");
+        println!("         d [R] - Delete lines in range, saving them in register R");
+        println!("             e - Edit current line");
+        println!("            e! - Reload the file from disk, discarding changes");
+        println!("      f [TEXT] - Find text below current line");
+        println!("    f /RE/[iw] - Find regex below current line, i ignores case, w whole words");
+        println!("      F [TEXT] - Find text above current line");
+        println!("    F /RE/[iw] - Find regex above current line");
+        println!("             i - Insert new line below current line");
+        println!("             I - Insert new line above current line");
+        println!(
+            "     g/RE/CMDS - Run CMDS on each line in range matching RE, defaults to all lines"
+        );
+        println!("     v/RE/CMDS - Run CMDS on each line in range not matching RE");
+        println!("            kx - Mark current line as x, use 'x as an address");
+        println!("             k - List marks");
+        println!("             m - Print editor data");
+        println!("       E [ENC] - Print or change encoding, bom and nobom add or remove BOM");
+        println!("    L [ENDING] - Print or convert line endings: lf, crlf, cr, eol, noeol");
+        println!(" Z [FMT [LVL]] - Print or change compression: none, gzip, zstd, xz");
+        println!("        m ADDR - Move lines in range to after ADDR, 0 is the start of the file");
+        println!("        t ADDR - Copy lines in range to after ADDR");
+        println!("       j [SEP] - Join lines in range with optional SEP, defaults to .,.+1");
+        println!("  s/RE/REP/[F] - Substitute RE with REP in range, flags g, i, l (literal), N");
+        println!("             q - Quit, refuses if there are unsaved changes");
+        println!("             S - List settings");
+        println!("  S NAME VALUE - Change a setting");
+        println!("             Q - Quit without saving");
+        println!("         y [R] - Yank lines in range to register R, A-Z append to a-z");
+        println!("         x [R] - Put lines from register R below current line");
+        println!("         X [R] - Put lines from register R above current line");
+        println!("             u - Undo last command");
+        println!("             U - Redo last undone command");
+        println!(" p [NUM] [CON] - Print range or line NUM with optional CON lines of context");
+        println!("  w [FILENAME] - Write range or file to FILENAME or opened file location");
+        println!("           w - - Write range or file to stdout");
+        println!("   w >> [FILE] - Append range or file to FILE or opened file location");
+        println!(
+            "  W [FILENAME] - Write range or file to FILENAME or opened file location and quit"
+        );
+        println!(" wq [FILENAME] - Same as W");
+        println!("  n [FILENAME] - Print or change the file the buffer is written to");
+        println!(
+            "  o [FILENAME] - Open FILENAME in the current buffer, o! discards unsaved changes"
+        );
+        println!("  O [FILENAME] - Open FILENAME in a new buffer");
+        println!("  r [FILENAME] - Insert FILENAME after current line or ADDR, 0 for the start");
+        println!("          !CMD - Run shell CMD, ! alone repeats the last command");
+        println!("       N,M!CMD - Replace range with the output of CMD given the range as input");
+        println!("        r !CMD - Insert the output of CMD after current line or ADDR");
+        println!("        w !CMD - Pipe range or file to CMD");
+        println!("             b - List buffers");
+        println!("    b NUM|NAME - Switch to buffer");
+        println!("  B [NUM|NAME] - Close current or given buffer, B! discards unsaved changes");
     }
 
     fn print_line_with_num(&self, line: u32) {
-        println!("{}: {}", line + 1, self.contents[line as usize]);
+        println!("{}: {}", line + 1, self.buf().contents.line(line as usize));
     }
 
-    fn print_curr_line_with_num(&self) {
-        self.print_line_with_num(self.curr_line);
-    }
+    fn print_line(&mut self, range: Option<Range>, args: &[&str]) {
+        if range.is_some() {
+            if let Some((start, end)) = self.line_range(range) {
+                let context_lines = args.first().and_then(|a| a.parse().ok()).unwrap_or(0);
+                self.print_context(start, end, context_lines);
+                self.buf_mut().curr_line = end;
+            }
+            return;
+        }
+
+        if self.buf().contents.is_empty() {
+            println!("Buffer is empty");
+            return;
+        }
 
-    fn print_line(&mut self, args: &[&str]) {
         let line_num = if args.is_empty() {
-            self.curr_line
+            self.buf().curr_line
         } else {
-            let new_line = args[0].parse().unwrap_or(self.curr_line);
+            let new_line = args[0].parse().unwrap_or(self.buf().curr_line + 1);
             if new_line == 0 {
                 new_line
+            } else if new_line > self.buf().contents.len() as u32 {
+                println!("Invalid address");
+                return;
             } else {
                 new_line - 1
             }
@@ -196,165 +586,1459 @@ impl Editor {
             args[1].parse::<i32>().unwrap_or(0)
         };
 
-        self.print_context(line_num, context_lines);
+        self.print_context(line_num, line_num, context_lines);
     }
 
     fn edit_mode(&mut self) {
-        let edited_line = self.terminal.edit_line(
-            &format!("{} # ", self.curr_line + 1),
-            &self.contents[self.curr_line as usize],
-        );
-        self.contents[self.curr_line as usize] = edited_line;
+        if self.buf().contents.is_empty() {
+            println!("Buffer is empty");
+            return;
+        }
+
+        let buffer = &mut self.buffers[self.current];
+        let curr_line = buffer.curr_line;
+        let line = buffer.contents.line(curr_line as usize);
+
+        // Lines with invalid UTF-8 or control characters are edited escaped
+        let escaped = !line.is_plain();
+        if escaped {
+            println!("Special characters are shown as \\xHH and backslashes as \\\\");
+        }
+
+        let edited_line = self
+            .terminal
+            .edit_line(&format!("{} # ", curr_line + 1), &line.edit_text());
+        let edited_line = Line::from_edit_text(edited_line, escaped);
+        if edited_line.as_bytes() != line.as_bytes() {
+            buffer.set_line(curr_line, edited_line);
+        }
     }
 
     fn insert_down(&mut self) {
         let new_line = self.terminal.readline("+ ");
-        self.curr_line += 1;
-        self.contents.insert(self.curr_line as usize, new_line);
+        let buffer = self.buf_mut();
+        if !buffer.contents.is_empty() {
+            buffer.curr_line += 1;
+        }
+        buffer.insert_line(buffer.curr_line, new_line.into());
     }
 
     fn insert_up(&mut self) {
         let new_line = self.terminal.readline("+ ");
-        self.contents.insert(self.curr_line as usize, new_line);
+        let buffer = self.buf_mut();
+        buffer.insert_line(buffer.curr_line, new_line.into());
     }
 
-    fn save(&mut self, args: &[&str]) {
-        if args.is_empty() {
-            match &self.filename {
-                Some(f) => self.save_file(&f),
-                None => println!("No filename given"),
+    // Write the buffer or a range of it. "w >> FILENAME" appends instead and
+    // "w !CMD" pipes the lines to CMD. The buffer only remembers FILENAME if it
+    // didn't have a file yet.
+    fn save(&mut self, range: Option<Range>, arg_str: &str) {
+        if let Some(shell_cmd) = arg_str.trim_start().strip_prefix('!') {
+            self.write_command(range, shell_cmd);
+            return;
+        }
+
+        let (append, name) = match arg_str.trim().strip_prefix(">>") {
+            Some(rest) => (true, rest.split_whitespace().next()),
+            None => (false, arg_str.split_whitespace().next()),
+        };
+
+        let (start, end) = if range.is_some() {
+            match self.line_range(range) {
+                Some((start, end)) => (start as usize, end as usize + 1),
+                None => return,
             }
-        } else if let Ok(p) = PathBuf::from_str(args[0]) {
-            self.save_file(&p);
-            self.filename = Some(p);
         } else {
-            println!("Invalid file name");
+            (0, self.buf().contents.len())
+        };
+
+        if name == Some("-") {
+            self.write_stdout(start, end);
+            return;
         }
-    }
 
-    fn save_file<P: AsRef<Path>>(&self, path: P) {
-        let mut the_file = match File::create(&path) {
-            Ok(f) => f,
-            Err(e) => {
-                println!("{}", e);
+        let path = match (name, &self.buf().filename) {
+            (Some(f), _) => PathBuf::from(f),
+            (None, Some(f)) => f.clone(),
+            (None, None) => {
+                println!("No filename given");
                 return;
             }
         };
 
-        let mut first = true;
+        if !self.check_lock(&path) {
+            return;
+        }
 
-        for line in &self.contents {
-            if !first {
-                if let Err(e) = the_file.write(self.newline_seq.as_bytes()) {
-                    println!("{}", e);
-                    return;
-                }
-            }
-            first = false;
+        if append {
+            self.append_file(&path, start, end);
+            return;
+        }
 
-            if let Err(e) = the_file.write(line.as_bytes()) {
-                println!("{}", e);
-                return;
+        if !self.confirm_overwrite(&path) || !self.backup(&path) {
+            return;
+        }
+
+        let format = self.format_for(&path);
+        let stamp = match Self::save_file(&path, self.buf().contents.range(start, end), &format) {
+            Some(s) => s,
+            None => return,
+        };
+
+        // Writing part of the buffer or a copy of it elsewhere shouldn't change
+        // where the buffer is saved
+        let buffer = self.buf_mut();
+        let named = range.is_none() && buffer.filename.is_none();
+        if named {
+            buffer.filename = Some(path.clone());
+        }
+        if buffer.filename.as_ref() == Some(&path) {
+            if range.is_none() {
+                buffer.modified = false;
             }
+            buffer.stamp = Some(stamp);
         }
 
-        println!("Saved!");
+        if named {
+            self.lock_buffer(self.current);
+        }
     }
 
-    fn metadata(&mut self) {
-        match &self.filename {
-            Some(f) => println!("File: {:?}", f),
-            None => println!("File: -"),
+    // Lock the buffer's file so other poe sessions know it's being edited. If
+    // another session already has it locked, ask whether to open it read-only.
+    fn lock_buffer(&mut self, idx: usize) {
+        self.release_lock(idx);
+
+        let path = match &self.buffers[idx].filename {
+            Some(f) => f.clone(),
+            None => return,
         };
-        println!("Lines: {}", self.contents.len());
-        println!("Current Line: {}", self.curr_line + 1);
-    }
 
-    fn context_cmd(&mut self, args: &[&str]) {
-        let context_lines = if args.is_empty() {
-            2
-        } else {
-            args[0].parse::<i32>().unwrap_or(2)
+        let owner = match lock::acquire(&path) {
+            Ok(Ok(lock_file)) => {
+                self.buffers[idx].lock = Some(lock_file);
+                return;
+            }
+            Ok(Err(owner)) => owner,
+            // Locks are only advisory, files that can't be locked, e.g. in a
+            // read-only directory, can still be edited
+            Err(_) => return,
         };
 
-        self.print_context(self.curr_line, context_lines);
+        println!("{} is locked by {}", path.display(), owner);
+        loop {
+            match self
+                .terminal
+                .readline("[r]ead-only, [e]dit anyway? ")
+                .trim()
+            {
+                "r" => {
+                    self.buffers[idx].read_only = true;
+                    return;
+                }
+                "e" => return,
+                _ => {}
+            }
+        }
     }
 
-    fn print_context(&mut self, line_num: u32, context_lines: i32) {
-        let context_before = {
-            let before = line_num as i32 - context_lines;
-            if before < 0 {
-                0
-            } else {
-                before as u32
+    fn release_lock(&mut self, idx: usize) {
+        let buffer = &mut self.buffers[idx];
+        buffer.read_only = false;
+
+        if let Some(lock_file) = buffer.lock.take() {
+            // The same file may be open in another buffer
+            if !self
+                .buffers
+                .iter()
+                .any(|b| b.lock.as_ref() == Some(&lock_file))
+            {
+                lock::release(&lock_file);
             }
-        };
+        }
+    }
 
-        let context_after = {
-            let after = line_num as i32 + context_lines;
-            if after >= self.contents.len() as i32 {
-                (after - (after - self.contents.len() as i32) - 1) as u32
-            } else {
-                after as u32
+    // A read-only buffer can only be written to its own file once the session
+    // that had it locked is done with it. Returns false if it's still locked.
+    fn check_lock(&mut self, path: &Path) -> bool {
+        let buffer = self.buf();
+        if !buffer.read_only || buffer.filename.as_deref() != Some(path) {
+            return true;
+        }
+
+        match lock::acquire(path) {
+            Ok(Err(owner)) => {
+                println!(
+                    "{} is locked by {}, use w FILENAME to write a copy",
+                    path.display(),
+                    owner
+                );
+                false
+            }
+            result => {
+                let buffer = self.buf_mut();
+                buffer.lock = result.ok().and_then(|r| r.ok());
+                buffer.read_only = false;
+                true
             }
+        }
+    }
+
+    // Check whether something else changed the buffer's file since it was read
+    // or written, and if so ask before overwriting it. Returns false if the
+    // file shouldn't be written.
+    fn confirm_overwrite(&mut self, path: &Path) -> bool {
+        let changed = match &self.buf().stamp {
+            Some(stamp) if stamp.path == path => stamp.changed(),
+            _ => return true,
         };
 
-        for x in context_before..line_num {
-            let line_num = x as usize;
-            println!("{}: {}", line_num + 1, self.contents[line_num]);
+        match changed {
+            Ok(false) => return true,
+            Ok(true) => println!("{} has changed on disk since it was read", path.display()),
+            Err(e) => println!("Can't check {} for changes: {}", path.display(), e),
+        }
+
+        loop {
+            let answer = self
+                .terminal
+                .readline("[o]verwrite, [r]eload, [d]iff, [c]ancel? ");
+
+            match answer.trim() {
+                "o" => return true,
+                "r" => {
+                    self.reload();
+                    return false;
+                }
+                "d" => {
+                    let disk = match buffer::read_file(path, Some(self.buf().format.encoding)) {
+                        Ok((lines, _, _)) => lines,
+                        Err(e) => {
+                            println!("Error reading {}: {}", path.display(), e);
+                            continue;
+                        }
+                    };
+
+                    let buffer = self.buf();
+                    let changed = diff::print_diff(
+                        &disk,
+                        &buffer.contents,
+                        &format!("{} (on disk)", path.display()),
+                        &buffer.name(),
+                    );
+                    if !changed {
+                        println!("No differences");
+                    }
+                }
+                "c" => return false,
+                _ => {}
+            }
         }
+    }
 
-        self.print_line_with_num(line_num);
+    // w - writes to stdout, in the buffer's format, so poe can be used in a
+    // pipeline.
+    fn write_stdout(&self, start: usize, end: usize) {
+        let buffer = self.buf();
+        let mut stdout: Box<dyn Write> = match &self.stdout {
+            Some(f) => Box::new(BufWriter::new(f)),
+            None => Box::new(io::stdout().lock()),
+        };
+        let result = buffer
+            .format
+            .write_lines(&mut stdout, buffer.contents.range(start, end))
+            .and_then(|()| stdout.flush());
 
-        for x in line_num + 1..=context_after {
-            let line_num = x as usize;
-            println!("{}: {}", line_num + 1, self.contents[line_num]);
+        if let Err(e) = result {
+            println!("Error writing to stdout: {}", e);
         }
     }
 
-    fn delete_line(&mut self) {
-        self.contents.remove(self.curr_line as usize);
-        if self.curr_line > 0 {
-            self.curr_line -= 1;
+    // The format to write the buffer to path in. Copies of the buffer are
+    // compressed going by their extension rather than like the buffer's file.
+    fn format_for(&self, path: &Path) -> FileFormat {
+        let buffer = self.buf();
+        match &buffer.filename {
+            Some(f) if f != path => FileFormat {
+                compression: Compression::for_path(path),
+                ..buffer.format
+            },
+            _ => buffer.format,
         }
     }
 
-    fn find_next(&mut self, args: &[&str]) {
-        let pattern: String = args.join(" ");
+    // Compressed lines are appended as a new stream, which decompresses to
+    // the file's text followed by the lines.
+    fn append_file(&mut self, path: &Path, start: usize, end: usize) {
+        // A byte order mark only belongs at the start of a file
+        let format = FileFormat {
+            bom: false,
+            ..self.format_for(path)
+        };
+        let lines = self.buf().contents.range(start, end);
 
-        for (x, line) in self
-            .contents
-            .iter()
-            .skip((self.curr_line as usize) + 1)
-            .enumerate()
-        {
-            if line.contains(&pattern) {
-                self.curr_line += (x + 1) as u32;
-                self.print_curr_line_with_num();
-                return;
+        let result = file::append(path, |f| {
+            format
+                .compression
+                .write(f, |mut w| format.write_lines(&mut w, lines))
+        });
+        match result {
+            Ok(()) => {
+                println!("Appended {} lines to {}", end - start, path.display());
+
+                // Don't warn about the append when the file is next saved
+                let buffer = self.buf_mut();
+                if buffer.filename.as_deref() == Some(path) {
+                    buffer.stamp = Stamp::read(path).ok();
+                }
             }
+            Err(e) => println!("Error appending to {}: {}", path.display(), e),
         }
+    }
 
-        println!("Pattern '{}' not found.", pattern);
+    // Print or change the file the buffer is saved to
+    fn filename_cmd(&mut self, args: &[&str]) {
+        match args.first() {
+            Some(f) => {
+                self.buf_mut().set_filename(PathBuf::from(f));
+                self.lock_buffer(self.current);
+            }
+            None => match &self.buf().filename {
+                Some(f) => println!("{}", f.display()),
+                None => println!("No filename"),
+            },
+        }
     }
 
-    fn find_prev(&mut self, args: &[&str]) {
-        let pattern: String = args.join(" ");
+    // Back up a file before it's overwritten for the first time this session.
+    // Returns false if the backup failed and the file shouldn't be written.
+    fn backup(&mut self, path: &Path) -> bool {
+        if self.backed_up.contains(path) {
+            return true;
+        }
 
-        for (x, line) in self
-            .contents
-            .iter()
-            .rev()
-            .skip(self.contents.len() - (self.curr_line as usize))
-            .enumerate()
-        {
-            if line.contains(&pattern) {
-                self.curr_line -= (x + 1) as u32;
-                self.print_curr_line_with_num();
-                return;
+        match file::backup(
+            path,
+            self.settings.backup,
+            self.settings.backup_dir.as_deref(),
+        ) {
+            Ok(backup) => {
+                if let Some(b) = backup {
+                    println!("Backed up to {}", b.display());
+                }
+                self.backed_up.insert(path.to_owned());
+                true
+            }
+            Err(e) => {
+                println!("Backup of {} failed, not saving: {}", path.display(), e);
+                false
             }
         }
+    }
+
+    // Write swap files for buffers with unsaved changes and remove those that
+    // are no longer needed.
+    fn update_swap_files(&mut self) {
+        let interval = self.settings.swap_interval;
+
+        for buffer in &mut self.buffers {
+            let wanted = match &buffer.filename {
+                // The swap file of a read-only buffer belongs to the session
+                // that has the file locked
+                Some(f) if buffer.modified && interval > 0 && !buffer.read_only => {
+                    Some(swap::swap_path(f))
+                }
+                _ => None,
+            };
+
+            if buffer.swap_file.is_some() && buffer.swap_file != wanted {
+                swap::remove(buffer.swap_file.as_ref().unwrap());
+                buffer.swap_file = None;
+                buffer.swap_journal.reset();
+            }
+
+            let path = match wanted {
+                Some(p) if buffer.swap_file.is_none() || buffer.swap_pending >= interval => p,
+                _ => continue,
+            };
+
+            let new = buffer.swap_file.is_none();
+            match swap::write(&path, &buffer.contents, &mut buffer.swap_journal, new) {
+                Ok(()) => buffer.swap_failed = false,
+                Err(e) => {
+                    if !buffer.swap_failed {
+                        println!("Can't write swap file {}: {}", path.display(), e);
+                    }
+                    buffer.swap_failed = true;
+                }
+            }
+            buffer.swap_file = Some(path);
+            buffer.swap_pending = 0;
+        }
+    }
+
+    // Offer to recover a buffer from a swap file left behind by a poe that
+    // didn't exit cleanly.
+    fn check_swap(&mut self, idx: usize) {
+        let path = match &self.buffers[idx].filename {
+            Some(f) => swap::swap_path(f),
+            None => return,
+        };
+
+        if !path.exists() {
+            return;
+        }
+
+        let swap_file = match swap::read(&path) {
+            Ok(s) => s,
+            Err(e) => {
+                println!("Ignoring swap file {}: {}", path.display(), e);
+                return;
+            }
+        };
+
+        println!("Found swap file {}", path.display());
+        if file::process_running(swap_file.pid) {
+            println!(
+                "It may be in use by another poe process (pid {})",
+                swap_file.pid
+            );
+        }
+
+        loop {
+            let answer = self.terminal.readline("[r]ecover, [d]iff, [x] discard? ");
+            let buffer = &mut self.buffers[idx];
+
+            match answer.trim() {
+                "r" => {
+                    // When opened by a command, recovering is part of its undo
+                    // step, otherwise it gets one of its own
+                    let count = swap_file.lines.len();
+                    let own_step = !buffer.history.in_step();
+                    if own_step {
+                        buffer.history.begin(buffer.curr_line);
+                    }
+                    buffer.edit(0, buffer.contents.len(), swap_file.lines);
+                    buffer.set_current_line(buffer.curr_line);
+                    if own_step {
+                        buffer.history.commit(buffer.curr_line);
+                    }
+                    buffer.swap_file = Some(path);
+                    buffer.swap_journal.reset();
+                    println!("Recovered {} lines, undo to go back to the file", count);
+                    return;
+                }
+                "d" => {
+                    let changed = diff::print_diff(
+                        &buffer.contents,
+                        &swap_file.lines,
+                        &buffer.name(),
+                        &path.display().to_string(),
+                    );
+                    if !changed {
+                        println!("No differences");
+                    }
+                }
+                "x" => {
+                    swap::remove(&path);
+                    return;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // List settings, or change one.
+    fn settings_cmd(&mut self, args: &[&str]) {
+        match args {
+            [] => self.settings.print(),
+            [name] => println!("Missing value for {}", name),
+            [name, value] => {
+                if let Err(e) = self.set_option(name, value) {
+                    println!("{}", e);
+                }
+            }
+            _ => println!("Usage: S [NAME VALUE]"),
+        }
+    }
+
+    // Returns a stamp of the written file, or None if it wasn't written.
+    fn save_file<'a, P, I>(path: P, lines: I, format: &FileFormat) -> Option<Stamp>
+    where
+        P: AsRef<Path>,
+        I: IntoIterator<Item = LineRef<'a>>,
+    {
+        let path = path.as_ref();
+        let mut crc = 0;
+        let result = file::write_atomic(path, |f| {
+            let mut w = Crc::new(f);
+            format
+                .compression
+                .write(&mut w, |mut c| format.write_lines(&mut c, lines))?;
+            crc = w.sum();
+            Ok(())
+        })
+        .and_then(|()| fs::metadata(path));
+
+        match result {
+            Ok(meta) => {
+                println!("Saved!");
+                Some(Stamp::new(path, &meta, crc))
+            }
+            Err(e) => {
+                println!("Error saving {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    fn metadata(&mut self) {
+        let buffer = self.buf();
+        match &buffer.filename {
+            Some(f) => println!("File: {:?}", f),
+            None => println!("File: -"),
+        };
+        println!("Buffer: {} of {}", self.current + 1, self.buffers.len());
+        println!("Lines: {}", buffer.contents.len());
+        println!("Encoding: {}", buffer.format.encoding_name());
+        println!("Line Endings: {}", buffer.format.line_endings_name());
+        println!(
+            "Final Newline: {}",
+            if buffer.format.final_newline {
+                "yes"
+            } else {
+                "no"
+            }
+        );
+        println!("Compression: {}", buffer.format.compression);
+        println!("Current Line: {}", buffer.curr_line + 1);
+        println!("Modified: {}", if buffer.modified { "yes" } else { "no" });
+        println!("Read Only: {}", if buffer.read_only { "yes" } else { "no" });
+    }
+
+    // Print the buffer's encoding, or change the encoding it's saved in. bom
+    // and nobom add or remove the byte order mark. Switching from UTF-8 also
+    // decodes lines that weren't valid UTF-8 in the new encoding.
+    fn encoding_cmd(&mut self, args: &[&str]) {
+        let buffer = self.buf_mut();
+        let mut format = buffer.format;
+
+        match args {
+            [] => {
+                println!("{}", format.encoding_name());
+                return;
+            }
+            ["bom"] => format.bom = true,
+            ["nobom"] => format.bom = false,
+            [name] => match Encoding::for_label(name.as_bytes()) {
+                Some(encoding) => format.encoding = encoding,
+                None => {
+                    println!("Unknown encoding '{}'", name);
+                    return;
+                }
+            },
+            _ => {
+                println!("Usage: E [ENCODING|bom|nobom]");
+                return;
+            }
+        }
+
+        if format.bom && !format.is_unicode() {
+            if args == ["bom"] {
+                println!("{} has no byte order mark", format.encoding.name());
+                return;
+            }
+            format.bom = false;
+        }
+
+        // Lines of a file read as UTF-8 that weren't valid UTF-8 are still the
+        // bytes from the file, so they can be read again in the new encoding
+        if buffer.format.encoding == UTF_8
+            && format.encoding != UTF_8
+            && format.encoding.is_ascii_compatible()
+        {
+            let failed = buffer.redecode(format.encoding);
+            if failed > 0 {
+                println!("{} lines aren't valid {}", failed, format.encoding.name());
+            }
+        }
+
+        buffer.set_format(format);
+    }
+
+    // Print the buffer's line endings, or convert them. eol and noeol add or
+    // remove the newline after the last line.
+    fn line_ending_cmd(&mut self, args: &[&str]) {
+        let buffer = self.buf_mut();
+        let mut format = buffer.format;
+
+        match args {
+            [] => {
+                println!("{}", format.line_endings_name());
+                return;
+            }
+            ["eol"] => format.final_newline = true,
+            ["noeol"] => format.final_newline = false,
+            [name] => match LineEnding::parse(name) {
+                Some(ending) => {
+                    format.line_ending = ending;
+                    format.mixed_endings = false;
+                    buffer.clear_line_endings();
+                }
+                None => {
+                    println!("Unknown line ending '{}', use lf, crlf or cr", name);
+                    return;
+                }
+            },
+            _ => {
+                println!("Usage: L [lf|crlf|cr|eol|noeol]");
+                return;
+            }
+        }
+
+        buffer.set_format(format);
+    }
+
+    // Print how the buffer's file is compressed, or change the compression
+    // and level it's saved with.
+    fn compression_cmd(&mut self, args: &[&str]) {
+        let buffer = self.buf_mut();
+
+        let compression = match args {
+            [] => {
+                println!("{}", buffer.format.compression);
+                return;
+            }
+            [name] => Compression::parse(name, None),
+            [name, level] => Compression::parse(name, Some(level)),
+            _ => None,
+        };
+
+        match compression {
+            Some(compression) => buffer.set_format(FileFormat {
+                compression,
+                ..buffer.format
+            }),
+            None => println!("Usage: Z [none|gzip|zstd|xz] [LEVEL]"),
+        }
+    }
+
+    // Mark a line with a single lowercase letter, or list marks if no name is
+    // given.
+    fn mark_cmd(&mut self, line: u32, args: &str) {
+        let name = args.trim();
+        if name.is_empty() {
+            for (name, line) in &self.buf().marks {
+                println!(
+                    "'{} {}: {}",
+                    name,
+                    line + 1,
+                    self.buf().contents.line(*line as usize)
+                );
+            }
+            return;
+        }
+
+        let mut chars = name.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) if c.is_ascii_lowercase() => {
+                self.buf_mut().marks.insert(c, line);
+            }
+            _ => println!("Mark names must be a single letter a-z"),
+        }
+    }
+
+    fn list_buffers(&self) {
+        for (i, buffer) in self.buffers.iter().enumerate() {
+            println!(
+                "{}{:>3} {} {} ({} lines)",
+                if i == self.current { '*' } else { ' ' },
+                i + 1,
+                if buffer.modified { '+' } else { ' ' },
+                buffer.name(),
+                buffer.contents.len()
+            );
+        }
+    }
+
+    // Find a buffer by number or name. Names match the full path, the file name
+    // or, if it's unique, any part of the path.
+    fn find_buffer(&self, name: &str) -> Option<usize> {
+        if let Ok(n) = name.parse::<usize>() {
+            if n > 0 && n <= self.buffers.len() {
+                return Some(n - 1);
+            }
+            println!("No buffer {}", n);
+            return None;
+        }
+
+        let file_name_matches = |b: &Buffer| {
+            b.filename
+                .as_ref()
+                .and_then(|f| f.file_name())
+                .is_some_and(|f| f == name)
+        };
+
+        if let Some(i) = self.buffers.iter().position(|b| b.name() == name) {
+            return Some(i);
+        }
+        if let Some(i) = self.buffers.iter().position(file_name_matches) {
+            return Some(i);
+        }
+
+        let matches: Vec<usize> = (0..self.buffers.len())
+            .filter(|i| self.buffers[*i].name().contains(name))
+            .collect();
+
+        match matches.len() {
+            1 => Some(matches[0]),
+            0 => {
+                println!("No buffer matching '{}'", name);
+                None
+            }
+            _ => {
+                println!("Buffer name '{}' is ambiguous", name);
+                None
+            }
+        }
+    }
+
+    // List buffers, or switch to the buffer given by number or name.
+    fn buffer_cmd(&mut self, args: &str) {
+        let name = args.trim();
+        if name.is_empty() {
+            self.list_buffers();
+            return;
+        }
+
+        if let Some(i) = self.find_buffer(name) {
+            self.current = i;
+            println!("{}: {}", i + 1, self.buf().name());
+        }
+    }
+
+    // Close the current buffer or the buffer given by number or name. Buffers
+    // with unsaved changes are only closed when forced with B!.
+    fn close_buffer(&mut self, args: &str) {
+        let (force, name) = match args.strip_prefix('!') {
+            Some(rest) => (true, rest.trim()),
+            None => (false, args.trim()),
+        };
+
+        let i = if name.is_empty() {
+            self.current
+        } else {
+            match self.find_buffer(name) {
+                Some(i) => i,
+                None => return,
+            }
+        };
+
+        if self.buffers[i].modified && !force {
+            println!(
+                "Buffer {} has unsaved changes, use B! to close it anyway",
+                self.buffers[i].name()
+            );
+            return;
+        }
+
+        self.release_lock(i);
+        let buffer = self.buffers.remove(i);
+        if let Some(swap_file) = buffer.swap_file {
+            swap::remove(&swap_file);
+        }
+
+        // Always keep at least one buffer open
+        if self.buffers.is_empty() {
+            let mut buffer = Buffer::new();
+            buffer.history.set_depth(self.settings.undo_depth);
+            self.buffers.push(buffer);
+        }
+
+        if self.current > i || self.current == self.buffers.len() {
+            self.current -= 1;
+        }
+    }
+
+    // Open a file in a new buffer and switch to it.
+    fn open_buffer(&mut self, args: &[&str]) {
+        if args.is_empty() {
+            println!("Invalid file name");
+            return;
+        }
+
+        let path = PathBuf::from(args[0]);
+        if let Some(i) = self
+            .buffers
+            .iter()
+            .position(|b| b.filename.as_ref() == Some(&path))
+        {
+            self.current = i;
+            println!("{}: {}", i + 1, self.buf().name());
+            return;
+        }
+
+        match self.add_buffer(path) {
+            Ok(idx) => {
+                self.current = idx;
+                println!("{}: {}", self.current + 1, self.buf().name());
+            }
+            Err(e) => println!("{}", e),
+        }
+    }
+
+    fn context_cmd(&mut self, start: u32, end: u32, args: &[&str]) {
+        let context_lines = if args.is_empty() {
+            2
+        } else {
+            args[0].parse::<i32>().unwrap_or(2)
+        };
+
+        self.print_context(start, end, context_lines);
+    }
+
+    fn print_context(&mut self, start: u32, end: u32, context_lines: i32) {
+        let len = self.buf().contents.len() as u32;
+        if len == 0 {
+            return;
+        }
+
+        // Negative context is the same as none
+        let context = context_lines.max(0) as u32;
+        let from = start.saturating_sub(context);
+        let to = end.saturating_add(context).min(len - 1);
+
+        for x in from..=to {
+            self.print_line_with_num(x);
+        }
+    }
+
+    fn substitute(&mut self, start: u32, end: u32, args: &str) {
+        let sub = match Substitution::parse(args) {
+            Ok(s) => s,
+            Err(e) => {
+                println!("{}", e);
+                return;
+            }
+        };
+
+        let mut total = 0;
+        for line in start..=end {
+            if let Some((new_line, count)) =
+                sub.apply(self.buf().contents.line(line as usize).as_bytes())
+            {
+                self.buf_mut().set_line(line, new_line);
+                self.print_line_with_num(line);
+                self.buf_mut().curr_line = line;
+                total += count;
+            }
+        }
+
+        match total {
+            // Lines without a match are expected when run from a global command
+            0 if self.buf().global_lines.is_some() => {}
+            0 => println!("No match"),
+            1 => println!("1 substitution"),
+            n => println!("{} substitutions", n),
+        }
+    }
+
+    fn delete_lines(&mut self, start: u32, end: u32, reg: char) {
+        let removed = self.buf_mut().remove_lines(start, end);
+        self.buf_mut().curr_line = start.saturating_sub(1);
+        self.store_register(reg, removed);
+    }
+
+    // Parse the destination address of a move or copy. Returns the 1-based line
+    // the lines go after, 0 is the start of the buffer.
+    fn destination(&self, args: &str) -> Option<u32> {
+        match address::parse_range(args, self) {
+            Ok((Some((_, dest)), rest)) if rest.trim().is_empty() => Some(dest as u32),
+            Ok((None, _)) => {
+                println!("Missing destination address");
+                None
+            }
+            Ok(_) => {
+                println!("Invalid destination address");
+                None
+            }
+            Err(e) => {
+                println!("{}", e);
+                None
+            }
+        }
+    }
+
+    fn move_lines(&mut self, start: u32, end: u32, dest: u32) {
+        if dest > start && dest <= end {
+            println!("Destination is inside the range being moved");
+            return;
+        }
+
+        let count = end - start + 1;
+        let to = if dest > end { dest - count } else { dest };
+
+        let buffer = self.buf_mut();
+        buffer.move_lines(start, end, to);
+        buffer.curr_line = to + count - 1;
+    }
+
+    fn copy_lines(&mut self, start: u32, end: u32, dest: u32) {
+        let buffer = self.buf_mut();
+        let lines = buffer.contents.copy(start as usize, end as usize + 1);
+        buffer.insert_lines(dest, lines);
+        buffer.curr_line = dest + end - start;
+    }
+
+    // Join lines into one, separated by the text after the command. The
+    // separator can be quoted to keep surrounding spaces.
+    fn join_lines(&mut self, start: u32, end: u32, args: &str) {
+        let sep = args.trim();
+        let sep = sep
+            .strip_prefix('"')
+            .and_then(|s| s.strip_suffix('"'))
+            .unwrap_or(sep);
+
+        let buffer = self.buf_mut();
+        if start < end {
+            let joined = Line::join(buffer.contents.range(start as usize, end as usize + 1), sep);
+            buffer.edit(start, (end - start + 1) as usize, Text::from(vec![joined]));
+        }
+        buffer.curr_line = start;
+    }
+
+    fn yank(&mut self, start: u32, end: u32, reg: char) {
+        let lines = self.buf().contents.copy(start as usize, end as usize + 1);
+        match lines.len() {
+            1 => println!("Yanked 1 line"),
+            n => println!("Yanked {} lines", n),
+        }
+        self.store_register(reg, lines);
+    }
+
+    // Store lines in a register and the unnamed register. Uppercase register
+    // names append to the lowercase register.
+    fn store_register(&mut self, reg: char, mut lines: Text) {
+        if reg.is_ascii_uppercase() {
+            let existing = self.registers.entry(reg.to_ascii_lowercase()).or_default();
+            existing.splice(existing.len(), 0, lines);
+            lines = existing.clone();
+        } else if reg != UNNAMED_REGISTER {
+            self.registers.insert(reg, lines.clone());
+        }
+
+        self.registers.insert(UNNAMED_REGISTER, lines);
+    }
+
+    // Insert the contents of a register after the addressed line, or before it
+    // when above is set. Address 0 puts the lines at the start of the buffer.
+    fn put(&mut self, range: Option<Range>, reg: char, above: bool) {
+        let lines = match self.registers.get(&reg.to_ascii_lowercase()) {
+            Some(lines) if !lines.is_empty() => lines.clone(),
+            _ => {
+                println!("Register {} is empty", reg);
+                return;
+            }
+        };
+
+        let line = match range {
+            Some((_, 0)) => 0,
+            Some((_, end)) if above => end as u32 - 1,
+            Some((_, end)) => end as u32,
+            None if self.buf().contents.is_empty() => 0,
+            None if above => self.buf().curr_line,
+            None => self.buf().curr_line + 1,
+        };
+
+        let count = lines.len() as u32;
+        let buffer = self.buf_mut();
+        buffer.insert_lines(line, lines);
+        buffer.curr_line = line + count - 1;
+    }
+
+    // Insert the lines of a file after the addressed line, or the current line.
+    // 0 inserts them at the start of the buffer. Without a filename the
+    // buffer's own file is read.
+    fn read_file(&mut self, range: Option<Range>, args: &[&str]) {
+        let path = match (args.first(), &self.buf().filename) {
+            (Some(f), _) => PathBuf::from(f),
+            (None, Some(f)) => f.clone(),
+            (None, None) => {
+                println!("No filename given");
+                return;
+            }
+        };
+
+        let lines = match buffer::read_file(&path, self.settings.encoding) {
+            Ok((lines, _, _)) => lines,
+            Err(e) => {
+                println!("Error reading {}: {}", path.display(), e);
+                return;
+            }
+        };
+        let bytes = fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+        let count = lines.len();
+
+        self.insert_read_lines(range, lines);
+        println!("Read {} lines, {} bytes", count, bytes);
+    }
+
+    // Insert lines read by r after the current or addressed line and make the
+    // last of them current.
+    fn insert_read_lines(&mut self, range: Option<Range>, lines: Text) {
+        let count = lines.len() as u32;
+        if count == 0 {
+            return;
+        }
+
+        let line = match range {
+            Some((_, end)) => end as u32,
+            None if self.buf().contents.is_empty() => 0,
+            None => self.buf().curr_line + 1,
+        };
+
+        let buffer = self.buf_mut();
+        buffer.insert_lines(line, lines);
+        buffer.curr_line = line + count - 1;
+    }
+
+    // The shell command to run, or the previous one if cmd is empty.
+    fn shell_command(&mut self, cmd: &str) -> Option<String> {
+        let cmd = cmd.trim();
+        if !cmd.is_empty() {
+            self.last_shell_cmd = Some(cmd.to_owned());
+            return Some(cmd.to_owned());
+        }
+
+        match &self.last_shell_cmd {
+            Some(last) => {
+                println!("{}", last);
+                Some(last.clone())
+            }
+            None => {
+                println!("No previous command");
+                None
+            }
+        }
+    }
+
+    // !CMD runs CMD on the terminal, N,M!CMD replaces the lines in range with
+    // the output of CMD given them as input.
+    fn shell(&mut self, range: Option<Range>, arg_str: &str) {
+        let cmd = match self.shell_command(arg_str) {
+            Some(c) => c,
+            None => return,
+        };
+
+        if range.is_some() {
+            if let Some((start, end)) = self.line_range(range) {
+                self.filter_lines(start, end, &cmd);
+            }
+            return;
+        }
+
+        match shell::run(&cmd, None, false) {
+            Ok((_, status)) => match shell::failure(status) {
+                Some(msg) => println!("{}", msg),
+                None => println!("!"),
+            },
+            Err(e) => println!("Error running {}: {}", cmd, e),
+        }
+    }
+
+    fn filter_lines(&mut self, start: u32, end: u32, cmd: &str) {
+        let input = Self::shell_input(self.buf().contents.range(start as usize, end as usize + 1));
+        let output = match Self::run_capture(cmd, Some(&input)) {
+            Some(lines) => lines,
+            None => return,
+        };
+
+        // The last filtered line becomes current, or the line before the range
+        // if the command printed nothing
+        let count = output.len() as u32;
+        let buffer = self.buf_mut();
+        buffer.edit(start, (end - start + 1) as usize, output);
+        buffer.curr_line = (start + count).saturating_sub(1);
+
+        println!("Filtered {} lines into {}", end - start + 1, count);
+    }
+
+    fn read_command(&mut self, range: Option<Range>, arg_str: &str) {
+        let cmd = match self.shell_command(arg_str) {
+            Some(c) => c,
+            None => return,
+        };
+
+        if let Some(lines) = Self::run_capture(&cmd, None) {
+            let count = lines.len();
+            self.insert_read_lines(range, lines);
+            println!("Read {} lines", count);
+        }
+    }
+
+    // Pipe the buffer or range to a command
+    fn write_command(&mut self, range: Option<Range>, arg_str: &str) {
+        let cmd = match self.shell_command(arg_str) {
+            Some(c) => c,
+            None => return,
+        };
+
+        let (start, end) = if range.is_some() {
+            match self.line_range(range) {
+                Some((start, end)) => (start as usize, end as usize + 1),
+                None => return,
+            }
+        } else {
+            (0, self.buf().contents.len())
+        };
+
+        let input = Self::shell_input(self.buf().contents.range(start, end));
+        match shell::run(&cmd, Some(&input), false) {
+            Ok((_, status)) => match shell::failure(status) {
+                Some(msg) => println!("{}", msg),
+                None => println!("Wrote {} lines to {}", end - start, cmd),
+            },
+            Err(e) => println!("Error running {}: {}", cmd, e),
+        }
+    }
+
+    // Lines are given to commands as UTF-8 with LF line endings whatever the
+    // file's format, the same as they're shown on the terminal.
+    fn shell_input<'a, I: IntoIterator<Item = LineRef<'a>>>(lines: I) -> Vec<u8> {
+        let mut input = Vec::new();
+        // Writing UTF-8 to memory can't fail
+        let _ = FileFormat::new().write_lines(&mut input, lines);
+        input
+    }
+
+    // Run a command and split its output into lines. Returns None and leaves
+    // the buffer alone if the command fails.
+    fn run_capture(cmd: &str, input: Option<&[u8]>) -> Option<Text> {
+        let (output, status) = match shell::run(cmd, input, true) {
+            Ok(r) => r,
+            Err(e) => {
+                println!("Error running {}: {}", cmd, e);
+                return None;
+            }
+        };
+
+        if let Some(msg) = shell::failure(status) {
+            println!("{}, buffer not changed", msg);
+            return None;
+        }
+
+        match FileFormat::read(output.as_slice(), Some(encoding_rs::UTF_8)) {
+            Ok((lines, _)) => Some(lines),
+            Err(e) => {
+                println!("Error reading output of {}: {}", cmd, e);
+                None
+            }
+        }
+    }
+
+    // Run a command list on every line in range that matches (or with invert,
+    // doesn't match) a pattern. Commands are separated by |, \| is a literal |.
+    fn global(&mut self, range: Option<Range>, args: &str, invert: bool) -> bool {
+        if self.buf().global_lines.is_some() {
+            println!("Global commands can't be nested");
+            return true;
+        }
+
+        let (start, end) = match range {
+            Some(_) => match self.line_range(range) {
+                Some(r) => r,
+                None => return true,
+            },
+            None if self.buf().contents.is_empty() => {
+                println!("Buffer is empty");
+                return true;
+            }
+            None => (0, self.buf().contents.len() as u32 - 1),
+        };
+
+        let mut chars = args.chars();
+        let delim = match chars.next() {
+            Some(c) if !c.is_whitespace() && c != '\\' => c,
+            _ => {
+                println!("Invalid global command");
+                return true;
+            }
+        };
+
+        let (pattern, cmds) = split_delimited(chars.as_str(), delim);
+        if !pattern.is_empty() {
+            match Search::regex(&pattern) {
+                Ok(s) => self.last_search = Some(s),
+                Err(e) => {
+                    println!("{}", e);
+                    return true;
+                }
+            }
+        }
+
+        let search = match &self.last_search {
+            Some(s) => s,
+            None => {
+                println!("No previous search");
+                return true;
+            }
+        };
+
+        let mut lines: Vec<u32> = (start..=end)
+            .filter(|l| {
+                search
+                    .find(self.buf().contents.line(*l as usize).as_bytes())
+                    .is_some()
+                    != invert
+            })
+            .collect();
+
+        if lines.is_empty() {
+            println!("No match");
+            return true;
+        }
+
+        let cmd_list = split_commands(cmds.unwrap_or(""));
+
+        // Lines are popped from the back, so visit them in reverse
+        lines.reverse();
+        self.buf_mut().global_lines = Some(lines);
+
+        let mut keep_running = true;
+        while let Some(line) = self.buf_mut().global_lines.as_mut().and_then(|l| l.pop()) {
+            self.buf_mut().curr_line = line;
+
+            for cmd in &cmd_list {
+                if !self.run_command(cmd) {
+                    keep_running = false;
+                    break;
+                }
+            }
+
+            if !keep_running {
+                break;
+            }
+        }
+
+        // A command may have switched buffers, so clear them all
+        for buffer in &mut self.buffers {
+            buffer.global_lines = None;
+        }
+        keep_running
+    }
+
+    // Parse a search pattern and remember it as the last search. An empty pattern
+    // reuses the last search.
+    fn set_search(&mut self, args: &str) -> bool {
+        match Search::parse(args) {
+            Ok(Some(search)) => {
+                self.last_search = Some(search);
+                true
+            }
+            Ok(None) if self.last_search.is_some() => true,
+            Ok(None) => {
+                println!("No previous search");
+                false
+            }
+            Err(e) => {
+                println!("{}", e);
+                false
+            }
+        }
+    }
+
+    fn print_match(&self, line: u32, col: usize) {
+        let text = self.buf().contents.line(line as usize);
+        let col = String::from_utf8_lossy(&text.as_bytes()[..col])
+            .chars()
+            .count();
+        println!("{}:{}: {}", line + 1, col + 1, text);
+    }
+
+    fn find_next(&mut self, args: &str) {
+        if !self.set_search(args) {
+            return;
+        }
+        let start = self.buf().curr_line as usize + 1;
+        self.find_from(start);
+    }
+
+    // Find the last search pattern, starting at 0-based line start
+    fn find_from(&mut self, start: usize) {
+        let search = self.last_search.as_ref().unwrap();
+        let contents = &self.buf().contents;
+        let start = start.min(contents.len());
+
+        let found = contents
+            .range(start, contents.len())
+            .enumerate()
+            .find_map(|(x, line)| search.find(line.as_bytes()).map(|col| (start + x, col)));
+
+        match found {
+            Some((line, col)) => {
+                self.buf_mut().curr_line = line as u32;
+                self.print_match(line as u32, col);
+            }
+            None => println!("Pattern '{}' not found.", search.text()),
+        }
+    }
+
+    fn find_prev(&mut self, args: &str) {
+        if !self.set_search(args) {
+            return;
+        }
+        let search = self.last_search.as_ref().unwrap();
+        let end = self.buf().curr_line as usize;
+
+        let found = self
+            .buf()
+            .contents
+            .range(0, end)
+            .rev()
+            .enumerate()
+            .find_map(|(x, line)| search.find(line.as_bytes()).map(|col| (end - x - 1, col)));
+
+        match found {
+            Some((line, col)) => {
+                self.buf_mut().curr_line = line as u32;
+                self.print_match(line as u32, col);
+            }
+            None => println!("Pattern '{}' not found.", search.text()),
+        }
+    }
+}
+
+const UNNAMED_REGISTER: char = '"';
+
+// Check what follows a command letter. Commands with ed-style arguments, like
+// s/a/b/, g/re/, t0 or !cmd, can have them attached. Commands that take no
+// argument or a single letter can't have anything more, and other arguments,
+// such as file names, need a space first so a typo like wx doesn't write a
+// file named x.
+fn valid_args(cmd: char, args: &str) -> bool {
+    let spaced = |a: &str| a.is_empty() || a.starts_with(char::is_whitespace);
+
+    match cmd {
+        's' | 'g' | 'v' | '!' | 'm' | 't' | 'f' | 'F' => true,
+        '?' | 'q' | 'Q' | 'u' | 'U' | 'i' | 'I' => args.trim().is_empty(),
+        'k' | 'd' | 'y' | 'x' | 'X' => args.split_whitespace().count() <= 1,
+        'e' => matches!(args.trim_end(), "" | "!"),
+        'o' | 'B' => spaced(args.strip_prefix('!').unwrap_or(args)),
+        'w' => spaced(args.strip_prefix('q').unwrap_or(args)),
+        _ => spaced(args),
+    }
+}
+
+// Parse an optional register name argument, written as x or "x. Prints an error
+// and returns None if the name is invalid.
+fn parse_register(args: &[&str]) -> Option<char> {
+    let name = match args.first() {
+        Some(a) => a.strip_prefix('"').unwrap_or(a),
+        None => return Some(UNNAMED_REGISTER),
+    };
+
+    let mut chars = name.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) if c.is_ascii_alphabetic() => Some(c),
+        (None, None) => Some(UNNAMED_REGISTER),
+        _ => {
+            println!("Register names must be a single letter a-z, or A-Z to append");
+            None
+        }
+    }
+}
+
+// Split a global command list on unescaped |. An empty list prints each line.
+fn split_commands(input: &str) -> Vec<String> {
+    let mut cmds = Vec::new();
+    let mut rest = Some(input);
+
+    while let Some(r) = rest {
+        let (cmd, next) = split_delimited(r, '|');
+        cmds.push(cmd);
+        rest = next;
+    }
+
+    if cmds.iter().all(|c| c.trim().is_empty()) {
+        return vec!["p".to_owned()];
+    }
+
+    cmds
+}
+
+impl address::Context for Editor {
+    fn current_line(&self) -> usize {
+        if self.buf().contents.is_empty() {
+            0
+        } else {
+            self.buf().curr_line as usize + 1
+        }
+    }
+
+    fn last_line(&self) -> usize {
+        self.buf().contents.len()
+    }
+
+    fn mark(&self, name: char) -> Option<usize> {
+        self.buf().marks.get(&name).map(|l| *l as usize + 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_file(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("poe-test-{}-{}", std::process::id(), name));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn lines(buffer: &Buffer) -> Vec<String> {
+        buffer.contents.iter().map(|l| l.to_string()).collect()
+    }
+
+    // A command that switches buffers part way through, as a global command
+    // can, still records its changes in the buffer it switched to
+    #[test]
+    fn undo_changes_after_switching_buffers() {
+        let f = temp_file("switch-f.txt", "root:x\nusr:y\n");
+        let g = temp_file("switch-g.txt", "a\nb\nc\n");
+        let mut editor = Editor::new_empty();
+        editor.add_buffer(&f).unwrap();
+        editor.add_buffer(&g).unwrap();
+
+        for cmd in ["b 2", "$d", "b 1", "g/usr/b 2|1d"] {
+            assert!(editor.execute(cmd));
+        }
+        assert_eq!(lines(&editor.buffers[1]), ["b"]);
+
+        assert!(editor.execute("u"));
+        assert_eq!(lines(&editor.buffers[1]), ["a", "b"]);
+        assert!(editor.execute("u"));
+        assert_eq!(lines(&editor.buffers[1]), ["a", "b", "c"]);
+        assert_eq!(lines(&editor.buffers[0]), ["root:x", "usr:y"]);
+
+        assert!(!editor.execute("Q"));
+        let _ = fs::remove_file(f);
+        let _ = fs::remove_file(g);
+    }
+
+    #[test]
+    fn changing_encoding_decodes_invalid_lines() {
+        let f = temp_file("encoding.txt", "");
+        fs::write(&f, b"caf\xc3\xa9\nna\xefve\n").unwrap();
+        let mut editor = Editor::new_empty();
+        editor.add_buffer(&f).unwrap();
+        assert_eq!(editor.buffers[0].format.encoding, UTF_8);
+
+        assert!(editor.execute("E latin1"));
+        assert_eq!(lines(&editor.buffers[0]), ["café", "naïve"]);
+        assert!(editor.execute("u"));
+        assert_eq!(editor.buffers[0].format.encoding, UTF_8);
+        assert_eq!(editor.buffers[0].contents.line(1).as_bytes(), b"na\xefve");
 
-        println!("Pattern '{}' not found.", pattern);
+        assert!(!editor.execute("Q"));
+        let _ = fs::remove_file(f);
     }
 }
@@ -0,0 +1,285 @@
+use std::fs::{self, File, Metadata, OpenOptions};
+use std::io::{self, BufWriter, Read, Write};
+use std::os::unix::fs::{fchown, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::SystemTime;
+
+use crate::settings::Backup;
+
+const MAX_SYMLINKS: usize = 40;
+
+// Write a file atomically. The contents are written to a temporary file in the
+// same directory, synced to disk and then renamed over the original, so a
+// failed write never leaves a truncated file behind. The original's mode and
+// ownership are kept and symlinks are written through rather than replaced.
+pub fn write_atomic<F>(path: &Path, write: F) -> io::Result<()>
+where
+    F: FnOnce(&mut BufWriter<&File>) -> io::Result<()>,
+{
+    let target = resolve_symlinks(path)?;
+    let original = fs::metadata(&target).ok();
+
+    let (tmp_path, tmp_file) = create_temp_file(&target)?;
+
+    let result = (|| {
+        if let Some(meta) = &original {
+            tmp_file.set_permissions(fs::Permissions::from_mode(meta.mode()))?;
+
+            // Changing the owner needs privileges, fall back to keeping the
+            // group and otherwise leave the file owned by the current user.
+            if fchown(&tmp_file, Some(meta.uid()), Some(meta.gid())).is_err() {
+                let _ = fchown(&tmp_file, None, Some(meta.gid()));
+            }
+        }
+
+        let mut writer = BufWriter::new(&tmp_file);
+        write(&mut writer)?;
+        writer.flush()?;
+        drop(writer);
+
+        tmp_file.sync_all()?;
+        fs::rename(&tmp_path, &target)
+    })();
+
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+        return result;
+    }
+
+    // Make sure the rename itself is on disk
+    if let Ok(dir) = File::open(parent_dir(&target)) {
+        let _ = dir.sync_all();
+    }
+
+    Ok(())
+}
+
+// Append to the end of a file, creating it if it doesn't exist.
+pub fn append<F>(path: &Path, write: F) -> io::Result<()>
+where
+    F: FnOnce(&mut BufWriter<&File>) -> io::Result<()>,
+{
+    let file = OpenOptions::new().append(true).create(true).open(path)?;
+
+    let mut writer = BufWriter::new(&file);
+    write(&mut writer)?;
+    writer.flush()?;
+    drop(writer);
+
+    file.sync_all()
+}
+
+// What a file looked like when it was last read or written, to tell whether
+// something else has changed it since.
+#[derive(Clone)]
+pub struct Stamp {
+    pub path: PathBuf,
+    mtime: Option<SystemTime>,
+    size: u64,
+    crc: u32,
+}
+
+impl Stamp {
+    pub fn new(path: &Path, meta: &Metadata, crc: u32) -> Stamp {
+        Stamp {
+            path: path.to_owned(),
+            mtime: meta.modified().ok(),
+            size: meta.len(),
+            crc,
+        }
+    }
+
+    // True if the file's contents are different from when the stamp was taken.
+    // The file is only read if it was touched without changing size. A file
+    // that has been deleted hasn't changed, writing it again loses nothing.
+    pub fn changed(&self) -> io::Result<bool> {
+        let meta = match fs::metadata(&self.path) {
+            Ok(m) => m,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e),
+        };
+
+        if meta.len() != self.size {
+            return Ok(true);
+        }
+        if meta.modified().ok() == self.mtime {
+            return Ok(false);
+        }
+
+        Ok(Stamp::read(&self.path)?.crc != self.crc)
+    }
+
+    // Stamp a file by reading it
+    pub fn read(path: &Path) -> io::Result<Stamp> {
+        let file = File::open(path)?;
+        let meta = file.metadata()?;
+
+        let mut reader = Crc::new(file);
+        io::copy(&mut reader, &mut io::sink())?;
+        Ok(Stamp::new(path, &meta, reader.sum()))
+    }
+}
+
+// Passes reads or writes through, keeping a checksum of the bytes.
+pub struct Crc<T> {
+    inner: T,
+    hasher: crc32fast::Hasher,
+}
+
+impl<T> Crc<T> {
+    pub fn new(inner: T) -> Crc<T> {
+        Crc {
+            inner,
+            hasher: crc32fast::Hasher::new(),
+        }
+    }
+
+    pub fn sum(&self) -> u32 {
+        self.hasher.clone().finalize()
+    }
+}
+
+impl<R: Read> Read for Crc<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+impl<W: Write> Write for Crc<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Follow symlinks to the file they point to. The final target doesn't need to
+// exist.
+pub fn resolve_symlinks(path: &Path) -> io::Result<PathBuf> {
+    let mut path = path.to_owned();
+
+    for _ in 0..MAX_SYMLINKS {
+        match fs::symlink_metadata(&path) {
+            Ok(meta) if meta.file_type().is_symlink() => {
+                let link = fs::read_link(&path)?;
+                path = parent_dir(&path).join(link);
+            }
+            _ => return Ok(path),
+        }
+    }
+
+    Err(io::Error::other("Too many levels of symbolic links"))
+}
+
+// A hidden file next to path, .NAME.EXT, for things like swap and lock files.
+// Symlinks are followed so all the names of a file share one.
+pub fn hidden_sibling(path: &Path, ext: &str) -> PathBuf {
+    let path = resolve_symlinks(path).unwrap_or_else(|_| path.to_owned());
+    path.with_file_name(format!(".{}.{}", file_name(&path), ext))
+}
+
+// True if a process other than this one has pid, e.g. the poe that left a swap
+// or lock file behind.
+pub fn process_running(pid: u32) -> bool {
+    if pid == process::id() {
+        return false;
+    }
+
+    // EPERM means the process exists but belongs to another user
+    (unsafe { libc::kill(pid as libc::pid_t, 0) }) == 0
+        || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+fn parent_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    }
+}
+
+fn create_temp_file(target: &Path) -> io::Result<(PathBuf, File)> {
+    let name = file_name(target);
+    let dir = parent_dir(target);
+
+    let mut n = 0;
+    loop {
+        let tmp_path = dir.join(format!(".{}.poe-{}-{}.tmp", name, process::id(), n));
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&tmp_path)
+        {
+            Ok(f) => return Ok((tmp_path, f)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists && n < 100 => n += 1,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// Copy an existing file to its backup location before it's overwritten. With a
+// backup directory, backups are named after the file's full path with / replaced
+// by !, so files with the same name don't share backups. Returns the path of the
+// backup, or None if there was nothing to back up.
+pub fn backup(path: &Path, mode: Backup, dir: Option<&Path>) -> io::Result<Option<PathBuf>> {
+    if mode == Backup::Off {
+        return Ok(None);
+    }
+
+    let target = resolve_symlinks(path)?;
+    if !target.is_file() {
+        return Ok(None);
+    }
+
+    let (dir, name) = match dir {
+        Some(d) => {
+            fs::create_dir_all(d)?;
+            let full_path = fs::canonicalize(&target)?;
+            (d, full_path.to_string_lossy().replace('/', "!"))
+        }
+        None => (parent_dir(&target), file_name(&target)),
+    };
+
+    let backup_path = match mode {
+        Backup::Numbered => {
+            let n = next_backup_number(dir, &name)?;
+            dir.join(format!("{}.~{}~", name, n))
+        }
+        _ => dir.join(format!("{}~", name)),
+    };
+
+    fs::copy(&target, &backup_path)?;
+    Ok(Some(backup_path))
+}
+
+fn next_backup_number(dir: &Path, name: &str) -> io::Result<u32> {
+    let prefix = format!("{}.~", name);
+    let mut max = 0;
+
+    for entry in fs::read_dir(dir)? {
+        let entry_name = entry?.file_name();
+        let n = entry_name
+            .to_string_lossy()
+            .strip_prefix(&prefix)
+            .and_then(|r| r.strip_suffix('~'))
+            .and_then(|n| n.parse().ok());
+
+        if let Some(n) = n {
+            max = max.max(n);
+        }
+    }
+
+    Ok(max + 1)
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
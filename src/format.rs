@@ -0,0 +1,475 @@
+// How a file's text is laid out on disk: its encoding, which line endings it
+// uses, whether the last line ends with one and how it's compressed.
+
+use std::borrow::Cow;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+
+use encoding_rs::{Encoding, UTF_16BE, UTF_16LE, UTF_8, WINDOWS_1252};
+use memchr::memchr2;
+
+use crate::compress::Compression;
+use crate::line::LineRef;
+use crate::text::Text;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LineEnding {
+    Lf,
+    CrLf,
+    Cr,
+}
+
+impl LineEnding {
+    pub fn parse(name: &str) -> Option<LineEnding> {
+        match name.to_ascii_lowercase().as_str() {
+            "lf" | "unix" => Some(LineEnding::Lf),
+            "crlf" | "dos" => Some(LineEnding::CrLf),
+            "cr" | "mac" => Some(LineEnding::Cr),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::CrLf => "\r\n",
+            LineEnding::Cr => "\r",
+        }
+    }
+}
+
+impl fmt::Display for LineEnding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            LineEnding::Lf => "LF",
+            LineEnding::CrLf => "CRLF",
+            LineEnding::Cr => "CR",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct FileFormat {
+    pub encoding: &'static Encoding,
+    pub bom: bool,
+    pub line_ending: LineEnding,
+    pub mixed_endings: bool, // Some lines have their own line ending
+    pub final_newline: bool,
+    pub compression: Compression,
+}
+
+impl Default for FileFormat {
+    fn default() -> FileFormat {
+        FileFormat::new()
+    }
+}
+
+impl FileFormat {
+    // Format for new files
+    pub fn new() -> FileFormat {
+        FileFormat {
+            encoding: UTF_8,
+            bom: false,
+            line_ending: LineEnding::Lf,
+            mixed_endings: false,
+            final_newline: true,
+            compression: Compression::None,
+        }
+    }
+
+    // Read a file and split it into lines. A byte order mark decides the
+    // encoding if there is one, then the given encoding. Otherwise the file is
+    // read as UTF-8, unless it has invalid UTF-8 and no valid non-ASCII
+    // characters, in which case it's read as Windows-1252. UTF-8 files are kept
+    // as they are, invalid bytes and all.
+    //
+    // Files in ASCII compatible encodings are split as they're read and each
+    // line is decoded on its own, so large files aren't held in memory twice.
+    pub fn read<R: BufRead>(
+        mut reader: R,
+        encoding: Option<&'static Encoding>,
+    ) -> io::Result<(Text, FileFormat)> {
+        let (bom, bom_len) = match Encoding::for_bom(reader.fill_buf()?) {
+            Some((e, len)) => (Some(e), len),
+            None => (None, 0),
+        };
+        reader.consume(bom_len);
+
+        let mut splitter = Splitter::new();
+        match bom.or(encoding) {
+            Some(e) if !e.is_ascii_compatible() => {
+                let mut bytes = Vec::new();
+                reader.read_to_end(&mut bytes)?;
+                splitter.feed(decode(e, &bytes)?.as_bytes());
+            }
+            _ => loop {
+                let buf = reader.fill_buf()?;
+                if buf.is_empty() {
+                    break;
+                }
+                splitter.feed(buf);
+                let len = buf.len();
+                reader.consume(len);
+            },
+        }
+
+        let encoding = match bom.or(encoding) {
+            Some(e) => e,
+            None if splitter.looks_like_utf8() => UTF_8,
+            None => WINDOWS_1252,
+        };
+
+        let (mut lines, mut format) = splitter.finish();
+        if encoding != UTF_8 && encoding.is_ascii_compatible() {
+            lines = lines.map_lines(|line, out| {
+                if line.is_ascii() {
+                    out.extend_from_slice(line);
+                } else {
+                    out.extend_from_slice(decode(encoding, line)?.as_bytes());
+                }
+                Ok::<_, io::Error>(())
+            })?;
+        }
+
+        format.encoding = encoding;
+        format.bom = bom.is_some();
+        Ok((lines, format))
+    }
+
+    // Write lines in the file's encoding. Fails if a line has characters the
+    // encoding can't represent.
+    pub fn write_lines<'a, W, I>(&self, w: &mut W, lines: I) -> io::Result<()>
+    where
+        W: Write,
+        I: IntoIterator<Item = LineRef<'a>>,
+    {
+        let mut bytes = Vec::new();
+        if self.bom {
+            self.encode("\u{FEFF}", &mut bytes);
+        }
+
+        let endings = ENDINGS.map(|e| {
+            let mut bytes = Vec::new();
+            self.encode(e.as_str(), &mut bytes);
+            bytes
+        });
+
+        let mut lines = lines.into_iter().enumerate().peekable();
+        while let Some((i, line)) = lines.next() {
+            if !self.encode(line.as_bytes(), &mut bytes) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "Line {} can't be encoded as {}",
+                        i + 1,
+                        self.encoding.name()
+                    ),
+                ));
+            }
+            if lines.peek().is_some() || self.final_newline {
+                let ending = line.ending().unwrap_or(self.line_ending);
+                bytes.extend_from_slice(&endings[ending as usize]);
+            }
+
+            w.write_all(&bytes)?;
+            bytes.clear();
+        }
+
+        w.write_all(&bytes)
+    }
+
+    // Append text in the file's encoding. Returns false if some characters
+    // couldn't be encoded.
+    fn encode<T: AsRef<[u8]> + ?Sized>(&self, text: &T, out: &mut Vec<u8>) -> bool {
+        let text = text.as_ref();
+
+        // UTF-8 is written as is so invalid bytes survive
+        if self.encoding == UTF_8 {
+            out.extend_from_slice(text);
+            return true;
+        }
+
+        let text = match std::str::from_utf8(text) {
+            Ok(t) => t,
+            Err(_) => return false,
+        };
+
+        // encoding_rs only decodes UTF-16, encoding it is left to us
+        if self.encoding == UTF_16LE {
+            out.extend(text.encode_utf16().flat_map(|c| c.to_le_bytes()));
+        } else if self.encoding == UTF_16BE {
+            out.extend(text.encode_utf16().flat_map(|c| c.to_be_bytes()));
+        } else {
+            let (bytes, _, had_errors) = self.encoding.encode(text);
+            if had_errors {
+                return false;
+            }
+            out.extend_from_slice(&bytes);
+        }
+        true
+    }
+
+    // Only Unicode encodings have a byte order mark
+    pub fn is_unicode(&self) -> bool {
+        self.encoding == UTF_8 || self.encoding == UTF_16LE || self.encoding == UTF_16BE
+    }
+
+    pub fn encoding_name(&self) -> String {
+        if self.bom {
+            format!("{} with BOM", self.encoding.name())
+        } else {
+            self.encoding.name().to_owned()
+        }
+    }
+
+    pub fn line_endings_name(&self) -> String {
+        if self.mixed_endings {
+            format!("mixed, mostly {}", self.line_ending)
+        } else {
+            self.line_ending.to_string()
+        }
+    }
+}
+
+fn decode<'a>(encoding: &'static Encoding, bytes: &'a [u8]) -> io::Result<Cow<'a, str>> {
+    encoding
+        .decode_without_bom_handling_and_without_replacement(bytes)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("File isn't valid {}", encoding.name()),
+            )
+        })
+}
+
+const ENDINGS: [LineEnding; 3] = [LineEnding::Lf, LineEnding::CrLf, LineEnding::Cr];
+
+// Splits text into lines on LF, CRLF or CR as it's read, keeping track of the
+// line endings seen and whether the text looks like UTF-8. Lines that don't end
+// like the first line are given their own ending, which finish fixes up once
+// the most common ending is known.
+struct Splitter {
+    lines: Text,
+    partial: Vec<u8>,   // Start of a line continued in the next read
+    pending_cr: bool,   // The last read ended with a CR, which may be part of a CRLF
+    counts: [usize; 3], // Number of each kind of line ending
+    first: Option<LineEnding>,
+    valid_utf8: bool, // Saw non-ASCII characters that are valid UTF-8
+    invalid_utf8: bool,
+}
+
+impl Splitter {
+    fn new() -> Splitter {
+        Splitter {
+            lines: Text::new(),
+            partial: Vec::new(),
+            pending_cr: false,
+            counts: [0; 3],
+            first: None,
+            valid_utf8: false,
+            invalid_utf8: false,
+        }
+    }
+
+    fn feed(&mut self, bytes: &[u8]) {
+        let mut i = 0;
+
+        if self.pending_cr {
+            self.pending_cr = false;
+            if bytes.first() == Some(&b'\n') {
+                self.end_line(LineEnding::CrLf);
+                i = 1;
+            } else {
+                self.end_line(LineEnding::Cr);
+            }
+        }
+
+        while let Some(n) = memchr2(b'\n', b'\r', &bytes[i..]) {
+            let end = i + n;
+            self.push(&bytes[i..end]);
+            i = end + 1;
+
+            if bytes[end] == b'\n' {
+                self.end_line(LineEnding::Lf);
+            } else if i == bytes.len() {
+                self.pending_cr = true;
+            } else if bytes[i] == b'\n' {
+                self.end_line(LineEnding::CrLf);
+                i += 1;
+            } else {
+                self.end_line(LineEnding::Cr);
+            }
+        }
+
+        self.partial.extend_from_slice(&bytes[i..]);
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        let line = if self.partial.is_empty() {
+            bytes
+        } else {
+            self.partial.extend_from_slice(bytes);
+            &self.partial
+        };
+
+        if !line.is_ascii() {
+            for chunk in line.utf8_chunks() {
+                self.valid_utf8 |= !chunk.valid().is_ascii();
+                self.invalid_utf8 |= !chunk.invalid().is_empty();
+            }
+        }
+
+        self.lines.push(LineRef::new(line, None));
+        self.partial.clear();
+    }
+
+    // Record the ending of the line just pushed
+    fn end_line(&mut self, ending: LineEnding) {
+        self.counts[ending as usize] += 1;
+        let first = *self.first.get_or_insert(ending);
+        if ending != first {
+            self.lines.set_last_ending(Some(ending));
+        }
+    }
+
+    // Legacy 8-bit text is very unlikely to contain valid UTF-8 sequences for
+    // non-ASCII characters, so text with any is taken to be UTF-8 with a few
+    // bad bytes.
+    fn looks_like_utf8(&self) -> bool {
+        self.valid_utf8 || !self.invalid_utf8
+    }
+
+    // The most common line ending becomes the file's, other lines keep their
+    // own so they're saved as they were read.
+    fn finish(mut self) -> (Text, FileFormat) {
+        if self.pending_cr {
+            self.end_line(LineEnding::Cr);
+        }
+
+        let final_newline = self.partial.is_empty();
+        if !final_newline {
+            self.push(&[]);
+        }
+
+        // Ties go to the first, more common, style
+        let mut most = LineEnding::Lf;
+        for ending in [LineEnding::CrLf, LineEnding::Cr] {
+            if self.counts[ending as usize] > self.counts[most as usize] {
+                most = ending;
+            }
+        }
+
+        let first = self.first.unwrap_or(most);
+        if first != most {
+            for ending in self.lines.endings_mut() {
+                match *ending {
+                    None => *ending = Some(first),
+                    Some(e) if e == most => *ending = None,
+                    _ => {}
+                }
+            }
+        }
+
+        let format = FileFormat {
+            encoding: UTF_8,
+            bom: false,
+            line_ending: most,
+            mixed_endings: self.counts.iter().filter(|c| **c > 0).count() > 1,
+            final_newline,
+            compression: Compression::None,
+        };
+
+        (self.lines, format)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::line::Line;
+
+    fn read(bytes: &[u8]) -> (Vec<Line>, FileFormat) {
+        let (text, format) = FileFormat::read(bytes, None).unwrap();
+        (text.iter().map(|l| l.to_line()).collect(), format)
+    }
+
+    fn write(lines: &[Line], format: &FileFormat) -> Vec<u8> {
+        let mut out = Vec::new();
+        format
+            .write_lines(&mut out, lines.iter().map(Line::as_line_ref))
+            .unwrap();
+        out
+    }
+
+    #[test]
+    fn splits_on_each_line_ending() {
+        for (bytes, ending) in [
+            (&b"a\nb\n"[..], LineEnding::Lf),
+            (b"a\r\nb\r\n", LineEnding::CrLf),
+            (b"a\rb\r", LineEnding::Cr),
+        ] {
+            let (lines, format) = read(bytes);
+            assert_eq!(lines, [Line::from("a"), Line::from("b")]);
+            assert_eq!(format.line_ending, ending);
+            assert!(!format.mixed_endings);
+            assert!(format.final_newline);
+            assert_eq!(write(&lines, &format), bytes);
+        }
+    }
+
+    #[test]
+    fn final_newline() {
+        let (lines, format) = read(b"a\r\nb");
+        assert_eq!(lines.len(), 2);
+        assert!(!format.final_newline);
+        assert_eq!(write(&lines, &format), b"a\r\nb");
+
+        let (lines, format) = read(b"");
+        assert!(lines.is_empty());
+        assert!(format.final_newline);
+    }
+
+    #[test]
+    fn crlf_split_across_reads() {
+        let mut splitter = Splitter::new();
+        splitter.feed(b"a\r");
+        splitter.feed(b"\nb\r");
+        let (lines, format) = splitter.finish();
+        assert_eq!(lines.len(), 2);
+        assert!(format.mixed_endings);
+        assert_eq!(format.line_ending, LineEnding::CrLf);
+        assert_eq!(lines.line(1).ending(), Some(LineEnding::Cr));
+    }
+
+    #[test]
+    fn mixed_endings_are_kept() {
+        let bytes = b"a\nb\r\nc\r\nd\re\r\n";
+        let (lines, format) = read(bytes);
+        assert_eq!(lines.len(), 5);
+        assert!(format.mixed_endings);
+        assert_eq!(format.line_ending, LineEnding::CrLf);
+        assert_eq!(lines[0].ending(), Some(LineEnding::Lf));
+        assert_eq!(lines[1].ending(), None);
+        assert_eq!(lines[3].ending(), Some(LineEnding::Cr));
+        assert_eq!(write(&lines, &format), bytes);
+
+        // New lines get the most common ending
+        let mut lines = lines;
+        lines.push(Line::from("f"));
+        assert!(write(&lines, &format).ends_with(b"e\r\nf\r\n"));
+    }
+
+    #[test]
+    fn detects_windows_1252() {
+        let (lines, format) = read(b"caf\xe9\n");
+        assert_eq!(format.encoding, WINDOWS_1252);
+        assert_eq!(lines[0], Line::from("café"));
+        assert_eq!(write(&lines, &format), b"caf\xe9\n");
+
+        // Valid UTF-8 elsewhere means the bad bytes are kept as they are
+        let (lines, format) = read(b"caf\xc3\xa9\ncaf\xe9\n");
+        assert_eq!(format.encoding, UTF_8);
+        assert_eq!(lines[1].as_bytes(), b"caf\xe9");
+    }
+}
@@ -0,0 +1,20 @@
+// The editor as a library, so the benchmarks can drive it the way the poe
+// binary does.
+
+mod address;
+mod buffer;
+mod compress;
+mod diff;
+pub mod editor;
+mod file;
+pub mod format;
+pub mod line;
+mod lock;
+mod search;
+mod settings;
+mod shell;
+mod substitute;
+mod swap;
+mod terminal;
+pub mod text;
+mod undo;
@@ -0,0 +1,248 @@
+// A line of text. Lines are kept as bytes rather than strings so files that
+// aren't valid UTF-8 can be edited and lines that weren't changed are written
+// back exactly as they were read.
+//
+// Lines in files with mixed line endings remember their ending when it isn't
+// the file's usual one, so saving doesn't change lines that weren't edited.
+//
+// A buffer's lines are stored together in a Text, which hands out LineRefs to
+// them. Line owns its bytes and is used for lines being made or changed.
+
+use std::borrow::Cow;
+use std::fmt::{self, Write};
+
+use crate::format::LineEnding;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Line {
+    bytes: Vec<u8>,
+    ending: Option<LineEnding>, // None for the file's line ending
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LineRef<'a> {
+    bytes: &'a [u8],
+    ending: Option<LineEnding>,
+}
+
+impl Line {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn ending(&self) -> Option<LineEnding> {
+        self.ending
+    }
+
+    pub fn set_ending(&mut self, ending: Option<LineEnding>) {
+        self.ending = ending;
+    }
+
+    pub fn as_line_ref(&self) -> LineRef<'_> {
+        LineRef::new(&self.bytes, self.ending)
+    }
+
+    pub fn join<'a, I: IntoIterator<Item = LineRef<'a>>>(lines: I, sep: &str) -> Line {
+        Line::from(
+            lines
+                .into_iter()
+                .map(|l| l.as_bytes())
+                .collect::<Vec<_>>()
+                .join(sep.as_bytes()),
+        )
+    }
+
+    pub fn from_edit_text(text: String, escaped: bool) -> Line {
+        if !escaped {
+            return Line::from(text);
+        }
+
+        let mut out = Vec::with_capacity(text.len());
+        let mut rest = text.as_str();
+
+        while let Some(i) = rest.find('\\') {
+            out.extend_from_slice(&rest.as_bytes()[..i]);
+            rest = &rest[i + 1..];
+
+            if let Some(r) = rest.strip_prefix('\\') {
+                out.push(b'\\');
+                rest = r;
+            } else if let Some(b) = rest
+                .strip_prefix('x')
+                .and_then(|r| r.get(..2))
+                .and_then(|h| u8::from_str_radix(h, 16).ok())
+            {
+                out.push(b);
+                rest = &rest[3..];
+            } else {
+                out.push(b'\\');
+            }
+        }
+
+        out.extend_from_slice(rest.as_bytes());
+        Line::from(out)
+    }
+}
+
+impl<'a> LineRef<'a> {
+    pub fn new(bytes: &'a [u8], ending: Option<LineEnding>) -> LineRef<'a> {
+        LineRef { bytes, ending }
+    }
+
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.bytes
+    }
+
+    pub fn ending(&self) -> Option<LineEnding> {
+        self.ending
+    }
+
+    pub fn to_line(&self) -> Line {
+        Line {
+            bytes: self.bytes.to_vec(),
+            ending: self.ending,
+        }
+    }
+
+    pub fn to_str_lossy(&self) -> Cow<'a, str> {
+        String::from_utf8_lossy(self.bytes)
+    }
+
+    // True if the line can be shown as is: valid UTF-8 without control
+    // characters other than tab.
+    pub fn is_plain(&self) -> bool {
+        match std::str::from_utf8(self.bytes) {
+            Ok(s) => !s.chars().any(|c| c != '\t' && c.is_control()),
+            Err(_) => false,
+        }
+    }
+
+    // Text to edit the line as. Lines that aren't plain are written with
+    // \xHH escapes for invalid bytes and control characters, and \\ for
+    // backslashes, so Line::from_edit_text can turn them back into the same
+    // bytes.
+    pub fn edit_text(&self) -> String {
+        if self.is_plain() {
+            return self.to_str_lossy().into_owned();
+        }
+
+        let mut out = String::with_capacity(self.bytes.len());
+        for chunk in self.bytes.utf8_chunks() {
+            for c in chunk.valid().chars() {
+                match c {
+                    '\\' => out.push_str("\\\\"),
+                    '\t' => out.push(c),
+                    c if c.is_control() => {
+                        for b in c.encode_utf8(&mut [0; 4]).bytes() {
+                            let _ = write!(out, "\\x{:02X}", b);
+                        }
+                    }
+                    c => out.push(c),
+                }
+            }
+            for b in chunk.invalid() {
+                let _ = write!(out, "\\x{:02X}", b);
+            }
+        }
+        out
+    }
+}
+
+impl From<String> for Line {
+    fn from(s: String) -> Line {
+        Line::from(s.into_bytes())
+    }
+}
+
+impl From<&str> for Line {
+    fn from(s: &str) -> Line {
+        Line::from(s.as_bytes())
+    }
+}
+
+impl From<Vec<u8>> for Line {
+    fn from(bytes: Vec<u8>) -> Line {
+        Line {
+            bytes,
+            ending: None,
+        }
+    }
+}
+
+impl From<&[u8]> for Line {
+    fn from(bytes: &[u8]) -> Line {
+        Line::from(bytes.to_vec())
+    }
+}
+
+impl AsRef<[u8]> for Line {
+    fn as_ref(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+// Lines are displayed with invalid bytes shown as \xHH and control characters
+// other than tab in caret notation, e.g. ^@ for NUL, so they can't mess up the
+// terminal.
+impl fmt::Display for LineRef<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for chunk in self.bytes.utf8_chunks() {
+            for c in chunk.valid().chars() {
+                match c as u32 {
+                    0x09 => f.write_char(c)?,
+                    n @ 0x00..=0x1F => write!(f, "^{}", (n as u8 + b'@') as char)?,
+                    0x7F => f.write_str("^?")?,
+                    n @ 0x80..=0x9F => write!(f, "\\u{{{:X}}}", n)?,
+                    _ => f.write_char(c)?,
+                }
+            }
+            for b in chunk.invalid() {
+                write!(f, "\\x{:02X}", b)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.as_line_ref().fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(bytes: &[u8]) -> Line {
+        let line = LineRef::new(bytes, None);
+        Line::from_edit_text(line.edit_text(), !line.is_plain())
+    }
+
+    #[test]
+    fn plain_lines_are_edited_as_is() {
+        let line = LineRef::new(b"tab\tand \\ backslash", None);
+        assert!(line.is_plain());
+        assert_eq!(line.edit_text(), "tab\tand \\ backslash");
+        assert_eq!(round_trip(line.as_bytes()), line.to_line());
+    }
+
+    #[test]
+    fn escaped_lines_round_trip() {
+        let line = LineRef::new(b"caf\xe9 \\ \x00\x1b[m \xc3\xa9", None);
+        assert!(!line.is_plain());
+        assert_eq!(line.edit_text(), "caf\\xE9 \\\\ \\x00\\x1B[m é");
+        assert_eq!(round_trip(line.as_bytes()), line.to_line());
+
+        for b in 0..=255u8 {
+            assert_eq!(round_trip(&[b'a', b, b'\\']).as_bytes(), [b'a', b, b'\\']);
+        }
+    }
+
+    #[test]
+    fn bad_escapes_are_kept() {
+        let line = Line::from_edit_text("\\xZZ \\q \\x4".to_string(), true);
+        assert_eq!(line.as_bytes(), b"\\xZZ \\q \\x4");
+        assert_eq!(Line::from_edit_text("\\x41".to_string(), true), "A".into());
+    }
+}
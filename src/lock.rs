@@ -0,0 +1,137 @@
+// Advisory locks so two poe sessions don't edit the same file. A lock is a
+// .NAME.lock file next to the edited file saying who has it open. Locks left
+// behind by a poe that was killed are taken over once its process is gone.
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use crate::file;
+
+const HEADER: &str = "poe lock file";
+
+pub struct Owner {
+    pub pid: u32,
+    pub host: String,
+    pub user: String,
+}
+
+impl Owner {
+    fn current() -> Owner {
+        Owner {
+            pid: process::id(),
+            host: hostname(),
+            user: env::var("USER")
+                .or_else(|_| env::var("LOGNAME"))
+                .unwrap_or_else(|_| unsafe { libc::getuid() }.to_string()),
+        }
+    }
+
+    fn is_current(&self) -> bool {
+        self.pid == process::id() && self.host == hostname()
+    }
+
+    // Only processes on this machine can be checked, locks from other hosts
+    // are assumed to be live.
+    fn is_live(&self) -> bool {
+        self.host != hostname() || file::process_running(self.pid)
+    }
+}
+
+impl fmt::Display for Owner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}@{} (pid {})", self.user, self.host, self.pid)
+    }
+}
+
+// The lock file for path, .NAME.lock in the same directory as the file.
+pub fn lock_path(path: &Path) -> PathBuf {
+    file::hidden_sibling(path, "lock")
+}
+
+// Lock a file. Returns the lock file's path, or who has the file locked if
+// another live session does.
+pub fn acquire(path: &Path) -> io::Result<Result<PathBuf, Owner>> {
+    let lock = lock_path(path);
+    let owner = Owner::current();
+
+    // The lock is written to a temporary file and linked into place, so other
+    // sessions never see a partly written lock
+    let tmp = lock.with_extension(format!("lock.{}", owner.pid));
+    fs::write(
+        &tmp,
+        format!(
+            "{}\npid: {}\nhost: {}\nuser: {}\n",
+            HEADER, owner.pid, owner.host, owner.user
+        ),
+    )?;
+    let result = link_lock(&tmp, &lock);
+    let _ = fs::remove_file(&tmp);
+    result
+}
+
+fn link_lock(tmp: &Path, lock: &Path) -> io::Result<Result<PathBuf, Owner>> {
+    loop {
+        match fs::hard_link(tmp, lock) {
+            Ok(()) => return Ok(Ok(lock.to_owned())),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+            Err(e) => return Err(e),
+        }
+
+        match read(lock) {
+            Ok(owner) if owner.is_current() => return Ok(Ok(lock.to_owned())),
+            Ok(owner) if owner.is_live() => return Ok(Err(owner)),
+            // The owner is gone or the lock is unreadable, take it over
+            _ => match fs::remove_file(lock) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            },
+        }
+    }
+}
+
+// Remove a lock, unless another session has taken it over since.
+pub fn release(lock: &Path) {
+    if let Ok(owner) = read(lock) {
+        if owner.is_current() {
+            let _ = fs::remove_file(lock);
+        }
+    }
+}
+
+fn read(lock: &Path) -> io::Result<Owner> {
+    let contents = fs::read_to_string(lock)?;
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid lock file");
+
+    let mut lines = contents.lines();
+    if lines.next() != Some(HEADER) {
+        return Err(invalid());
+    }
+
+    let mut field = |name: &str| -> io::Result<String> {
+        lines
+            .next()
+            .and_then(|l| l.strip_prefix(name))
+            .map(|v| v.trim().to_owned())
+            .ok_or_else(invalid)
+    };
+
+    Ok(Owner {
+        pid: field("pid:")?.parse().map_err(|_| invalid())?,
+        host: field("host:")?,
+        user: field("user:")?,
+    })
+}
+
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    if unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) } != 0 {
+        return String::new();
+    }
+
+    let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
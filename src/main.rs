@@ -1,28 +1,123 @@
-mod editor;
-mod terminal;
-
 use std::env;
+use std::io;
+use std::path::Path;
 use std::process;
 
-use editor::Editor;
+use poe::editor::Editor;
 
 fn main() {
-    let filenames: Vec<String> = env::args().collect();
-
-    let mut editor = if filenames.len() == 1 {
-        Editor::new_empty()
-    } else if filenames.len() == 2 {
-        match Editor::new(&filenames[1]) {
-            Ok(e) => e,
-            Err(err) => {
-                println!("{}", err);
-                process::exit(1);
+    let mut args = env::args().skip(1);
+    let mut filenames: Vec<(String, Option<String>)> = Vec::new();
+    let mut options = Vec::new();
+    let mut commands = Vec::new();
+    let mut jump = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--undo-depth" | "--backup" | "--backup-dir" | "--swap-interval" | "--encoding" => {
+                match args.next() {
+                    Some(value) => options.push((arg[2..].to_owned(), value)),
+                    None => usage(),
+                }
+            }
+            "-c" => match args.next() {
+                Some(cmd) => commands.push(cmd),
+                None => usage(),
+            },
+            _ if arg.starts_with('+') => jump = Some(arg[1..].to_owned()),
+            _ => {
+                let (name, line) = split_location(&arg);
+                filenames.push((name.to_owned(), jump.take().or(line)));
             }
         }
-    } else {
-        println!("Usage: poe [FILENAME]");
+    }
+
+    // A jump after the last file applies to it, as in poe file +42
+    if let (Some(spec), Some((_, file_jump))) = (jump, filenames.last_mut()) {
+        *file_jump = Some(spec);
+    }
+
+    let mut editor = Editor::new_empty();
+    if let Err(err) = editor.use_tty_for_output() {
+        println!("Can't use the terminal: {}", err);
         process::exit(1);
-    };
+    }
+
+    // Options come first as they change how files are loaded
+    for (name, value) in options {
+        if let Err(err) = editor.set_option(&name, &value) {
+            println!("{}", err);
+            process::exit(1);
+        }
+    }
+
+    for (f, jump) in &filenames {
+        let result = if f == "-" {
+            editor.add_stdin_buffer()
+        } else {
+            editor.add_buffer(f)
+        };
+
+        match result {
+            Ok(idx) => {
+                if let Some(spec) = jump {
+                    editor.jump(idx, spec);
+                }
+            }
+            Err(err) => exit_error(&mut editor, f, err),
+        }
+    }
+
+    for cmd in &commands {
+        if !editor.execute(cmd) {
+            return;
+        }
+    }
 
     editor.run();
 }
+
+// Split FILE:LINE or FILE:LINE:COLUMN, as printed by compilers and grep -n,
+// into the file and the line to start at. Files whose names look like that are
+// opened as they are.
+fn split_location(arg: &str) -> (&str, Option<String>) {
+    if Path::new(arg).exists() {
+        return (arg, None);
+    }
+
+    let mut name = arg.strip_suffix(':').unwrap_or(arg);
+    let mut line = None;
+    for _ in 0..2 {
+        match name.rsplit_once(':') {
+            Some((n, num)) if !n.is_empty() && is_number(num) => {
+                name = n;
+                line = Some(num.to_owned());
+            }
+            _ => break,
+        }
+    }
+
+    match line {
+        Some(_) => (name, line),
+        None => (arg, None),
+    }
+}
+
+fn is_number(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit())
+}
+
+fn usage() -> ! {
+    println!(
+        "Usage: poe [--undo-depth NUM] [--backup off|simple|numbered] [--backup-dir DIR]\n           [--swap-interval NUM] [--encoding ENC] [-c CMD]... [[+LINE|+/RE] FILENAME[:LINE]]..."
+    );
+    process::exit(1);
+}
+
+// Files opened before the one that failed are locked, so the editor is shut
+// down before exiting.
+fn exit_error(editor: &mut Editor, filename: &str, err: io::Error) -> ! {
+    editor.shutdown();
+    println!("{}: {}", filename, err);
+    process::exit(1);
+}
@@ -0,0 +1,196 @@
+use regex::bytes::{Regex, RegexBuilder};
+
+// A search pattern used by the find commands. Patterns written as /RE/FLAGS are
+// regular expressions, anything else is matched literally.
+pub struct Search {
+    text: String,
+    matcher: Matcher,
+}
+
+enum Matcher {
+    Literal(String),
+    Regex(Regex),
+}
+
+// i ignores case and w only matches whole words
+fn valid_flags(flags: &str) -> bool {
+    flags.trim().chars().all(|c| c == 'i' || c == 'w')
+}
+
+impl Search {
+    // Parse a search pattern. Returns None for an empty pattern, which means the
+    // previous search should be repeated. Text starting with / is only a
+    // regular expression if the closing / is followed by nothing but flags, so
+    // paths like /usr/bin are found literally. A leading \/ is a literal /.
+    pub fn parse(input: &str) -> Result<Option<Search>, String> {
+        let input = input.trim();
+        if input.is_empty() {
+            return Ok(None);
+        }
+
+        if let Some(rest) = input.strip_prefix('/') {
+            if let (pattern, Some(flags)) = split_delimited(rest, '/') {
+                if valid_flags(flags) {
+                    if pattern.is_empty() {
+                        return Ok(None);
+                    }
+                    return Search::with_flags(pattern, flags).map(Some);
+                }
+            }
+        }
+
+        let text = match input.strip_prefix("\\/") {
+            Some(rest) => format!("/{}", rest),
+            None => input.to_owned(),
+        };
+        Ok(Some(Search {
+            text: text.clone(),
+            matcher: Matcher::Literal(text),
+        }))
+    }
+
+    // Parse a regular expression written as /RE/FLAGS or just /RE, as in +/RE
+    // on the command line.
+    pub fn parse_regex(input: &str) -> Result<Search, String> {
+        let input = input.trim();
+        let rest = input.strip_prefix('/').unwrap_or(input);
+        match split_delimited(rest, '/') {
+            (pattern, Some(flags)) if valid_flags(flags) => Search::with_flags(pattern, flags),
+            _ => Search::regex(rest),
+        }
+    }
+
+    fn with_flags(pattern: String, flags: &str) -> Result<Search, String> {
+        let re = if flags.contains('w') {
+            format!(r"\b(?:{})\b", pattern)
+        } else {
+            pattern.clone()
+        };
+
+        let regex = RegexBuilder::new(&re)
+            .case_insensitive(flags.contains('i'))
+            .build()
+            .map_err(|e| format!("Invalid pattern: {}", e))?;
+
+        Ok(Search {
+            text: pattern,
+            matcher: Matcher::Regex(regex),
+        })
+    }
+
+    // Create a regular expression search.
+    pub fn regex(pattern: &str) -> Result<Search, String> {
+        let regex = Regex::new(pattern).map_err(|e| format!("Invalid pattern: {}", e))?;
+
+        Ok(Search {
+            text: pattern.to_owned(),
+            matcher: Matcher::Regex(regex),
+        })
+    }
+
+    // Returns the byte offset of the first match in line.
+    pub fn find(&self, line: &[u8]) -> Option<usize> {
+        match &self.matcher {
+            Matcher::Literal(s) => line.windows(s.len()).position(|w| w == s.as_bytes()),
+            Matcher::Regex(re) => re.find(line).map(|m| m.start()),
+        }
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+}
+
+// Split input at the first unescaped delim. Escaped delimiters in the first
+// part are unescaped. The second part is None if delim wasn't found.
+pub fn split_delimited(input: &str, delim: char) -> (String, Option<&str>) {
+    let mut part = String::with_capacity(input.len());
+    let mut chars = input.chars();
+
+    while let Some(c) = chars.next() {
+        if c == delim {
+            return (part, Some(chars.as_str()));
+        }
+
+        if c == '\\' {
+            match chars.next() {
+                Some(n) if n == delim => part.push(n),
+                Some(n) => {
+                    part.push(c);
+                    part.push(n);
+                }
+                None => part.push(c),
+            }
+        } else {
+            part.push(c);
+        }
+    }
+
+    (part, None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn search(input: &str) -> Search {
+        Search::parse(input).unwrap().unwrap()
+    }
+
+    fn is_regex(search: &Search) -> bool {
+        matches!(search.matcher, Matcher::Regex(_))
+    }
+
+    #[test]
+    fn regex_needs_closing_delimiter_and_flags() {
+        let s = search("/fo+/");
+        assert!(is_regex(&s));
+        assert_eq!(s.find(b"a foo"), Some(2));
+
+        let s = search("/FOO/ iw");
+        assert!(is_regex(&s));
+        assert_eq!(s.find(b"food foo"), Some(5));
+
+        let s = search(r"/a\/b/");
+        assert_eq!(s.text(), "a/b");
+        assert_eq!(s.find(b"xa/b"), Some(1));
+    }
+
+    #[test]
+    fn paths_are_literal() {
+        for input in ["/usr/bin", "/usr", "/", "/a.b/c/"] {
+            let s = search(input);
+            assert!(!is_regex(&s), "{}", input);
+            assert_eq!(s.text(), input);
+        }
+        assert_eq!(search("/usr/bin").find(b"#!/usr/bin/env"), Some(2));
+        assert_eq!(search("a.b").find(b"axb a.b"), Some(4));
+    }
+
+    #[test]
+    fn escaped_slash_is_literal() {
+        let s = search(r"\/tmp/i");
+        assert!(!is_regex(&s));
+        assert_eq!(s.text(), "/tmp/i");
+        assert_eq!(s.find(b"cd /tmp/i"), Some(3));
+        assert_eq!(s.find(b"cd /TMP/"), None);
+    }
+
+    #[test]
+    fn empty_pattern_repeats_search() {
+        assert!(Search::parse("").unwrap().is_none());
+        assert!(Search::parse("  ").unwrap().is_none());
+        assert!(Search::parse("//").unwrap().is_none());
+    }
+
+    #[test]
+    fn command_line_regex() {
+        let s = Search::parse_regex("/usr/bin").unwrap();
+        assert!(is_regex(&s));
+        assert_eq!(s.find(b"#!/usr/bin/env"), Some(3));
+
+        let s = Search::parse_regex("/^FN/i").unwrap();
+        assert_eq!(s.find(b"fn main"), Some(0));
+        assert!(Search::parse_regex("/(").is_err());
+    }
+}
@@ -0,0 +1,96 @@
+use std::path::PathBuf;
+
+use encoding_rs::Encoding;
+
+use crate::undo;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Backup {
+    Off,
+    Simple,   // file~
+    Numbered, // file.~N~
+}
+
+// Editor options, changed with the S command or command line flags
+pub struct Settings {
+    pub backup: Backup,
+    pub backup_dir: Option<PathBuf>,
+    pub undo_depth: usize,
+    pub swap_interval: usize,
+    pub encoding: Option<&'static Encoding>, // None means UTF-8, or Windows-1252 if not UTF-8
+}
+
+impl Settings {
+    pub fn new() -> Self {
+        Settings {
+            backup: Backup::Off,
+            backup_dir: None,
+            undo_depth: undo::DEFAULT_DEPTH,
+            swap_interval: 1,
+            encoding: None,
+        }
+    }
+
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        match name {
+            "backup" => {
+                self.backup = match value {
+                    "off" => Backup::Off,
+                    "simple" => Backup::Simple,
+                    "numbered" => Backup::Numbered,
+                    _ => return Err("backup must be off, simple or numbered".to_owned()),
+                }
+            }
+            "backup-dir" => {
+                self.backup_dir = if value.is_empty() || value == "none" {
+                    None
+                } else {
+                    Some(PathBuf::from(value))
+                }
+            }
+            "undo-depth" => {
+                self.undo_depth = value
+                    .parse()
+                    .map_err(|_| "undo-depth must be a number".to_owned())?
+            }
+            "swap-interval" => {
+                self.swap_interval = value
+                    .parse()
+                    .map_err(|_| "swap-interval must be a number".to_owned())?
+            }
+            "encoding" => {
+                self.encoding = if value == "auto" {
+                    None
+                } else {
+                    match Encoding::for_label(value.as_bytes()) {
+                        Some(e) => Some(e),
+                        None => return Err(format!("Unknown encoding '{}'", value)),
+                    }
+                }
+            }
+            _ => return Err(format!("Unknown setting '{}'", name)),
+        }
+
+        Ok(())
+    }
+
+    pub fn print(&self) {
+        let backup = match self.backup {
+            Backup::Off => "off",
+            Backup::Simple => "simple",
+            Backup::Numbered => "numbered",
+        };
+
+        println!("backup = {}", backup);
+        match &self.backup_dir {
+            Some(d) => println!("backup-dir = {}", d.display()),
+            None => println!("backup-dir = none"),
+        }
+        println!("undo-depth = {}", self.undo_depth);
+        println!("swap-interval = {}", self.swap_interval);
+        match self.encoding {
+            Some(e) => println!("encoding = {}", e.name()),
+            None => println!("encoding = auto"),
+        }
+    }
+}
@@ -0,0 +1,56 @@
+// Running shell commands for !, r !, w ! and filtering lines. Commands are run
+// with sh and their errors go straight to the terminal.
+
+use std::io::{self, Read, Write};
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Command, ExitStatus, Stdio};
+use std::thread;
+
+// Run a command with input written to its stdin, or the terminal's stdin if
+// there's no input. With capture, returns what the command wrote to stdout,
+// otherwise it's shown on the terminal.
+pub fn run(cmd: &str, input: Option<&[u8]>, capture: bool) -> io::Result<(Vec<u8>, ExitStatus)> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(cmd)
+        .stdin(if input.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::inherit()
+        })
+        .stdout(if capture {
+            Stdio::piped()
+        } else {
+            Stdio::inherit()
+        })
+        .spawn()?;
+
+    // Input is written from another thread so a command that writes a lot
+    // before reading everything can't deadlock with us
+    let stdin = child.stdin.take();
+    thread::scope(|s| {
+        if let (Some(mut stdin), Some(input)) = (stdin, input) {
+            // Commands like head stop reading early, that isn't an error
+            s.spawn(move || {
+                let _ = stdin.write_all(input);
+            });
+        }
+
+        let mut output = Vec::new();
+        if let Some(mut stdout) = child.stdout.take() {
+            stdout.read_to_end(&mut output)?;
+        }
+        let status = child.wait()?;
+        Ok((output, status))
+    })
+}
+
+// Describe how a command failed, None if it succeeded.
+pub fn failure(status: ExitStatus) -> Option<String> {
+    match (status.code(), status.signal()) {
+        (Some(0), _) => None,
+        (Some(code), _) => Some(format!("Exit status {}", code)),
+        (None, Some(signal)) => Some(format!("Killed by signal {}", signal)),
+        (None, None) => Some("Command failed".to_owned()),
+    }
+}
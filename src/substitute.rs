@@ -0,0 +1,242 @@
+use regex::bytes::{Captures, Regex, RegexBuilder};
+
+use crate::line::Line;
+use crate::search::split_delimited;
+
+// A parsed s/PATTERN/REPLACEMENT/FLAGS command.
+pub struct Substitution {
+    regex: Regex,
+    replacement: Vec<Part>,
+    global: bool,
+    nth: usize,
+}
+
+enum Part {
+    Text(String),
+    Group(usize),
+}
+
+impl Substitution {
+    // Parse the text following the s command. The first character is used as
+    // the delimiter.
+    pub fn parse(input: &str) -> Result<Substitution, String> {
+        let mut chars = input.chars();
+        let delim = match chars.next() {
+            Some(c) if !c.is_whitespace() && c != '\\' => c,
+            _ => return Err("Invalid substitution".to_owned()),
+        };
+
+        let (pattern, rest) = split_delimited(chars.as_str(), delim);
+        if pattern.is_empty() {
+            return Err("No pattern given".to_owned());
+        }
+
+        let (replacement, flags) = match rest {
+            Some(r) => split_delimited(r, delim),
+            None => (String::new(), None),
+        };
+
+        let mut global = false;
+        let mut case_insensitive = false;
+        let mut literal = false;
+        let mut nth = String::new();
+        for flag in flags.unwrap_or("").trim().chars() {
+            match flag {
+                'g' => global = true,
+                'i' => case_insensitive = true,
+                'l' => literal = true,
+                '0'..='9' => nth.push(flag),
+                _ => return Err(format!("Unknown substitution flag '{}'", flag)),
+            }
+        }
+
+        let nth = if nth.is_empty() {
+            1
+        } else {
+            match nth.parse() {
+                Ok(n) if n > 0 => n,
+                _ => return Err("Invalid occurrence number".to_owned()),
+            }
+        };
+
+        let pattern = if literal {
+            regex::escape(&pattern)
+        } else {
+            pattern
+        };
+
+        let regex = RegexBuilder::new(&pattern)
+            .case_insensitive(case_insensitive)
+            .build()
+            .map_err(|e| format!("Invalid pattern: {}", e))?;
+
+        let replacement = if literal {
+            vec![Part::Text(replacement)]
+        } else {
+            parse_replacement(&replacement, regex.captures_len())?
+        };
+
+        Ok(Substitution {
+            regex,
+            replacement,
+            global,
+            nth,
+        })
+    }
+
+    // Apply the substitution to line. Returns the new line and the number of
+    // replacements made, or None if nothing matched.
+    pub fn apply(&self, line: &[u8]) -> Option<(Line, usize)> {
+        let mut out = Vec::with_capacity(line.len());
+        let mut last = 0;
+        let mut count = 0;
+
+        for (i, caps) in self.regex.captures_iter(line).enumerate() {
+            if i + 1 < self.nth {
+                continue;
+            }
+            if i + 1 > self.nth && !self.global {
+                break;
+            }
+
+            let m = caps.get(0).unwrap();
+            out.extend_from_slice(&line[last..m.start()]);
+            self.expand(&caps, &mut out);
+            last = m.end();
+            count += 1;
+        }
+
+        if count == 0 {
+            return None;
+        }
+
+        out.extend_from_slice(&line[last..]);
+        Some((Line::from(out), count))
+    }
+
+    fn expand(&self, caps: &Captures, out: &mut Vec<u8>) {
+        for part in &self.replacement {
+            match part {
+                Part::Text(s) => out.extend_from_slice(s.as_bytes()),
+                Part::Group(n) => {
+                    if let Some(m) = caps.get(*n) {
+                        out.extend_from_slice(m.as_bytes());
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Parse a replacement string. & and \0 insert the whole match, \1 to \9 insert
+// capture groups and a backslash escapes any other character.
+fn parse_replacement(input: &str, groups: usize) -> Result<Vec<Part>, String> {
+    let mut parts = Vec::new();
+    let mut text = String::new();
+    let mut chars = input.chars();
+
+    while let Some(c) = chars.next() {
+        let group = match c {
+            '&' => 0,
+            '\\' => match chars.next() {
+                Some(n) if n.is_ascii_digit() => n.to_digit(10).unwrap() as usize,
+                Some(n) => {
+                    text.push(n);
+                    continue;
+                }
+                None => {
+                    text.push(c);
+                    continue;
+                }
+            },
+            _ => {
+                text.push(c);
+                continue;
+            }
+        };
+
+        if group >= groups {
+            return Err(format!("Invalid back reference \\{}", group));
+        }
+
+        if !text.is_empty() {
+            parts.push(Part::Text(std::mem::take(&mut text)));
+        }
+        parts.push(Part::Group(group));
+    }
+
+    if !text.is_empty() {
+        parts.push(Part::Text(text));
+    }
+
+    Ok(parts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Run s/.../ on text, None if nothing matched
+    fn sub(command: &str, text: &str) -> Option<(String, usize)> {
+        let s = Substitution::parse(command).unwrap();
+        s.apply(text.as_bytes())
+            .map(|(line, count)| (line.to_string(), count))
+    }
+
+    fn error(command: &str) -> String {
+        match Substitution::parse(command) {
+            Ok(_) => panic!("{} parsed", command),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn replaces_first_match() {
+        assert_eq!(sub("/o/0/", "foo boo"), Some(("f0o boo".into(), 1)));
+        assert_eq!(sub("/x/y/", "foo"), None);
+        assert_eq!(sub("/o", "foo"), Some(("fo".into(), 1)));
+        assert_eq!(sub("/o/", "foo"), Some(("fo".into(), 1)));
+    }
+
+    #[test]
+    fn flags() {
+        assert_eq!(sub("/o/0/g", "foo boo"), Some(("f00 b00".into(), 4)));
+        assert_eq!(sub("/o/0/3", "foo boo"), Some(("foo b0o".into(), 1)));
+        assert_eq!(sub("/o/0/2g", "foo boo"), Some(("fo0 b00".into(), 3)));
+        assert_eq!(sub("/o/0/5", "foo boo"), None);
+        assert_eq!(sub("/FOO/bar/i", "a Foo"), Some(("a bar".into(), 1)));
+        assert_eq!(sub("/a.c/&&/l", "abc a.c"), Some(("abc &&".into(), 1)));
+        assert_eq!(sub("/o/0/ g ", "oo"), Some(("00".into(), 2)));
+        assert_eq!(error("/o/0/x"), "Unknown substitution flag 'x'");
+        assert_eq!(error("/o/0/0"), "Invalid occurrence number");
+    }
+
+    #[test]
+    fn back_references() {
+        assert_eq!(sub("/o+/[&]/", "foo"), Some(("f[oo]".into(), 1)));
+        assert_eq!(sub("/o+/[\\0]/", "foo"), Some(("f[oo]".into(), 1)));
+        assert_eq!(
+            sub("/(\\w+) (\\w+)/\\2 \\1/", "hello world"),
+            Some(("world hello".into(), 1))
+        );
+        assert_eq!(sub("/(a)|(b)/<\\2>/g", "ab"), Some(("<><b>".into(), 2)));
+        assert_eq!(error("/(a)/\\2/"), "Invalid back reference \\2");
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(sub("/o/\\&/", "foo"), Some(("f&o".into(), 1)));
+        assert_eq!(sub("/o/\\\\/", "foo"), Some(("f\\o".into(), 1)));
+        assert_eq!(sub("/o/a\\/b/", "foo"), Some(("fa/bo".into(), 1)));
+        assert_eq!(sub("/\\//-/", "a/b"), Some(("a-b".into(), 1)));
+        assert_eq!(sub("|/|-|g", "a/b/c"), Some(("a-b-c".into(), 2)));
+    }
+
+    #[test]
+    fn invalid_commands() {
+        assert_eq!(error(""), "Invalid substitution");
+        assert_eq!(error(" o/0/"), "Invalid substitution");
+        assert_eq!(error("//x/"), "No pattern given");
+        assert!(error("/(/x/").starts_with("Invalid pattern"));
+    }
+}
@@ -0,0 +1,278 @@
+// Swap files hold the unsaved contents of a buffer next to the edited file so
+// edits can be recovered if poe is killed before saving.
+//
+// A swap file starts with a snapshot of the buffer's lines. Later changes are
+// appended as splices, so a small edit to a large file doesn't rewrite it all.
+// Once the splices add up to more than the snapshot, the swap file is
+// rewritten with a new snapshot.
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use crate::file;
+use crate::line::LineRef;
+use crate::text::Text;
+
+const HEADER: &str = "poe swap file";
+
+pub struct SwapFile {
+    pub pid: u32,
+    pub lines: Text,
+}
+
+// Replace count lines starting at line with lines
+struct Splice {
+    line: u32,
+    count: usize,
+    lines: Text,
+}
+
+// Changes made to a buffer since its swap file was last written, and how much
+// has been written to it.
+#[derive(Default)]
+pub struct Journal {
+    splices: Vec<Splice>,
+    pending_size: u64,
+    snapshot_size: u64,
+    journal_size: u64, // Size of the splices after the snapshot
+    rewrite: bool,     // The splices are incomplete, a new snapshot is needed
+}
+
+impl Journal {
+    // Keep a change to write to the swap file. Once appending the changes
+    // would cost more than writing a new snapshot they're dropped instead.
+    pub fn record(&mut self, line: u32, count: usize, lines: &Text) {
+        if self.rewrite {
+            return;
+        }
+
+        self.pending_size += record_size(lines);
+        if self.journal_size + self.pending_size > self.snapshot_size {
+            self.reset();
+            return;
+        }
+
+        self.splices.push(Splice {
+            line,
+            count,
+            lines: lines.clone(),
+        });
+    }
+
+    // Write a new snapshot next time, e.g. when the buffer was replaced
+    pub fn reset(&mut self) {
+        self.splices.clear();
+        self.pending_size = 0;
+        self.rewrite = true;
+    }
+}
+
+fn record_size(lines: &Text) -> u64 {
+    let header = 32;
+    header
+        + lines
+            .iter()
+            .map(|l| l.as_bytes().len() as u64 + 1)
+            .sum::<u64>()
+}
+
+// The swap file for path, .NAME.swp in the same directory as the file.
+pub fn swap_path(path: &Path) -> PathBuf {
+    file::hidden_sibling(path, "swp")
+}
+
+// Bring a swap file up to date with the buffer's lines, appending the
+// journal's changes to it or, for a new swap file, writing a snapshot.
+pub fn write(swap: &Path, lines: &Text, journal: &mut Journal, new: bool) -> io::Result<()> {
+    let result = if new || journal.rewrite {
+        write_snapshot(swap, lines, journal)
+    } else {
+        append(swap, journal)
+    };
+
+    // After a failed write the swap file's contents are unknown
+    journal.splices.clear();
+    journal.pending_size = 0;
+    journal.rewrite = result.is_err();
+    result
+}
+
+fn write_snapshot(swap: &Path, lines: &Text, journal: &mut Journal) -> io::Result<()> {
+    // Swap files contain the file's contents, so only the user may read them
+    let f = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(swap)?;
+    let mut w = BufWriter::new(f);
+
+    writeln!(w, "{}", HEADER)?;
+    writeln!(w, "pid: {}", process::id())?;
+    writeln!(w, "lines: {}", lines.len())?;
+    let mut size = 0;
+    for line in lines.iter() {
+        w.write_all(line.as_bytes())?;
+        w.write_all(b"\n")?;
+        size += line.as_bytes().len() as u64 + 1;
+    }
+
+    w.flush()?;
+    w.get_ref().sync_data()?;
+    journal.snapshot_size = size;
+    journal.journal_size = 0;
+    Ok(())
+}
+
+fn append(swap: &Path, journal: &mut Journal) -> io::Result<()> {
+    if journal.splices.is_empty() {
+        return Ok(());
+    }
+
+    let f = OpenOptions::new().append(true).open(swap)?;
+    let mut w = BufWriter::new(f);
+
+    for splice in &journal.splices {
+        writeln!(
+            w,
+            "splice: {} {} {}",
+            splice.line,
+            splice.count,
+            splice.lines.len()
+        )?;
+        for line in splice.lines.iter() {
+            w.write_all(line.as_bytes())?;
+            w.write_all(b"\n")?;
+        }
+    }
+
+    w.flush()?;
+    w.get_ref().sync_data()?;
+    journal.journal_size += journal.pending_size;
+    Ok(())
+}
+
+pub fn read(swap: &Path) -> io::Result<SwapFile> {
+    let contents = fs::read(swap)?;
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid swap file");
+
+    // Everything written is followed by a newline, anything after the last
+    // one was cut short by poe being killed while writing it
+    let end = contents
+        .iter()
+        .rposition(|b| *b == b'\n')
+        .ok_or_else(invalid)?;
+
+    // Lines are stored as bytes, only the headers are text
+    let mut lines = contents[..end].split(|b| *b == b'\n');
+    if lines.next() != Some(HEADER.as_bytes()) {
+        return Err(invalid());
+    }
+
+    let mut field = |name: &str| -> io::Result<usize> {
+        lines
+            .next()
+            .and_then(|l| std::str::from_utf8(l).ok())
+            .and_then(|l| l.strip_prefix(name))
+            .and_then(|v| v.trim().parse().ok())
+            .ok_or_else(invalid)
+    };
+
+    let pid = field("pid:")? as u32;
+    let count = field("lines:")?;
+
+    let mut text = Text::new();
+    for line in lines.by_ref().take(count) {
+        text.push(LineRef::new(line, None));
+    }
+    if text.len() != count {
+        return Err(invalid());
+    }
+
+    // Replay the splices after the snapshot, leaving out a last one that's
+    // missing lines
+    while let Some(header) = lines.next() {
+        let numbers: Option<Vec<usize>> = std::str::from_utf8(header)
+            .ok()
+            .and_then(|h| h.strip_prefix("splice:"))
+            .and_then(|h| h.split_whitespace().map(|n| n.parse().ok()).collect());
+        let (line, count, inserted) = match numbers.as_deref() {
+            Some(&[line, count, inserted]) => (line, count, inserted),
+            _ => return Err(invalid()),
+        };
+
+        let mut new = Text::new();
+        for line in lines.by_ref().take(inserted) {
+            new.push(LineRef::new(line, None));
+        }
+        if new.len() != inserted {
+            break;
+        }
+        if line + count > text.len() {
+            return Err(invalid());
+        }
+        text.splice(line, count, new);
+    }
+
+    Ok(SwapFile { pid, lines: text })
+}
+
+pub fn remove(swap: &Path) {
+    let _ = fs::remove_file(swap);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::line::Line;
+
+    fn text(lines: &[&str]) -> Text {
+        Text::from(lines.iter().map(|l| Line::from(*l)).collect::<Vec<_>>())
+    }
+
+    fn read_lines(path: &Path) -> Vec<String> {
+        let swap = read(path).unwrap();
+        assert_eq!(swap.pid, process::id());
+        swap.lines.iter().map(|l| l.to_string()).collect()
+    }
+
+    #[test]
+    fn splices_are_appended_and_replayed() {
+        let path = std::env::temp_dir().join(format!("poe-swap-test-{}", process::id()));
+        let mut journal = Journal::default();
+        let mut lines = text(&["a", "b", "c", "", "d"]);
+        write(&path, &lines, &mut journal, true).unwrap();
+        let snapshot = fs::metadata(&path).unwrap().len();
+
+        for (line, count, new) in [
+            (1, 1, text(&["B", "B2"])),
+            (5, 1, text(&[])),
+            (0, 0, text(&["z"])),
+        ] {
+            journal.record(line as u32, count, &new);
+            lines.splice(line, count, new);
+        }
+        write(&path, &lines, &mut journal, false).unwrap();
+        assert!(fs::metadata(&path).unwrap().len() > snapshot);
+        assert_eq!(read_lines(&path), ["z", "a", "B", "B2", "c", ""]);
+
+        // A splice cut short is left out
+        let mut f = OpenOptions::new().append(true).open(&path).unwrap();
+        f.write_all(b"splice: 0 1 2\nx\ny").unwrap();
+        assert_eq!(read_lines(&path), ["z", "a", "B", "B2", "c", ""]);
+
+        // Splices bigger than the snapshot get a new snapshot instead
+        let big = Text::from(vec![Line::from("x".repeat(100))]);
+        journal.record(0, 1, &big);
+        lines.splice(0, 1, big);
+        write(&path, &lines, &mut journal, false).unwrap();
+        let contents = fs::read(&path).unwrap();
+        assert!(!contents.windows(7).any(|w| w == b"splice:"));
+        assert_eq!(read_lines(&path).len(), 6);
+
+        remove(&path);
+    }
+}
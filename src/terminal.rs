@@ -1,4 +1,6 @@
+use std::fs::{File, OpenOptions};
 use std::io::{self, stdin, stdout, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
 
 use termion::event::Key;
 use termion::input::TermRead;
@@ -6,6 +8,41 @@ use termion::raw::IntoRawMode;
 
 const INPUT_BUF_SIZE: usize = 1024;
 
+// Read keys from the controlling terminal instead of stdin, for when stdin was
+// a pipe the buffer was read from.
+pub fn use_tty_for_input() -> io::Result<()> {
+    let tty = File::open("/dev/tty")?;
+    if unsafe { libc::dup2(tty.as_raw_fd(), libc::STDIN_FILENO) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Show prompts and messages on the controlling terminal when stdout isn't a
+// terminal, as in poe - | sort. Returns the original stdout for w - to write
+// to, or None if stdout is already the terminal or there's no terminal to use.
+pub fn use_tty_for_output() -> io::Result<Option<File>> {
+    if unsafe { libc::isatty(libc::STDOUT_FILENO) } == 1 {
+        return Ok(None);
+    }
+    let tty = match OpenOptions::new().write(true).open("/dev/tty") {
+        Ok(tty) => tty,
+        Err(_) => return Ok(None),
+    };
+
+    // Commands run by ! write to the terminal, so they don't get the original
+    let out = unsafe { libc::fcntl(libc::STDOUT_FILENO, libc::F_DUPFD_CLOEXEC, 0) };
+    if out < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let out = unsafe { File::from_raw_fd(out) };
+
+    if unsafe { libc::dup2(tty.as_raw_fd(), libc::STDOUT_FILENO) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(Some(out))
+}
+
 pub struct Terminal {
     history: Vec<String>,
     history_item: usize, // Index into history
@@ -19,7 +56,7 @@ impl Terminal {
         }
     }
 
-    #[allow(clippy::cognitive_complexity)]
+    #[allow(clippy::cognitive_complexity, clippy::collapsible_match)]
     pub fn edit_line(&mut self, prompt: &str, line: &str) -> String {
         let mut stdout = stdout()
             .into_raw_mode()
@@ -203,7 +240,7 @@ impl Terminal {
         buf[..buf_len].iter().collect()
     }
 
-    #[allow(clippy::cognitive_complexity)]
+    #[allow(clippy::cognitive_complexity, clippy::collapsible_match)]
     pub fn readline(&mut self, prompt: &str) -> String {
         let mut stdout = stdout()
             .into_raw_mode()
@@ -0,0 +1,530 @@
+// The lines of a buffer, kept in chunks so inserting or removing lines only
+// moves the lines in the affected chunks rather than every line after them.
+// Finding a line is a binary search over the chunks.
+//
+// Each chunk stores its lines' bytes one after another with where each line
+// ends, rather than each line in its own allocation, so a large file takes
+// little more memory than its size.
+
+use crate::format::LineEnding;
+use crate::line::{Line, LineRef};
+
+// Chunks are split when they grow past twice this size and merged with their
+// neighbour when both fit in one chunk.
+const CHUNK_SIZE: usize = 1024;
+
+#[derive(Clone, Default)]
+pub struct Text {
+    chunks: Vec<Chunk>,
+    starts: Vec<usize>, // Index of the first line of each chunk
+    len: usize,
+}
+
+#[derive(Clone, Default)]
+struct Chunk {
+    bytes: Vec<u8>,
+    ends: Vec<usize>, // Where each line ends in bytes
+    endings: Vec<Option<LineEnding>>,
+}
+
+impl Text {
+    pub fn new() -> Text {
+        Text::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // Add a line at the end. Used when loading files.
+    pub fn push(&mut self, line: LineRef) {
+        match self.chunks.last_mut() {
+            Some(chunk) if chunk.len() < CHUNK_SIZE => chunk.push(line),
+            last => {
+                // Full chunks won't grow any more while loading, only leave
+                // room for edits
+                if let Some(chunk) = last {
+                    chunk
+                        .bytes
+                        .shrink_to(chunk.bytes.len() + chunk.bytes.len() / 16);
+                }
+                let mut chunk = Chunk::default();
+                chunk.push(line);
+                self.starts.push(self.len);
+                self.chunks.push(chunk);
+            }
+        }
+        self.len += 1;
+    }
+
+    pub fn set_last_ending(&mut self, ending: Option<LineEnding>) {
+        if let Some(last) = self.chunks.last_mut().and_then(|c| c.endings.last_mut()) {
+            *last = ending;
+        }
+    }
+
+    pub fn endings_mut(&mut self) -> impl Iterator<Item = &mut Option<LineEnding>> {
+        self.chunks.iter_mut().flat_map(|c| c.endings.iter_mut())
+    }
+
+    pub fn line(&self, line: usize) -> LineRef<'_> {
+        assert!(line < self.len, "line out of bounds");
+        let (chunk, offset) = self.locate(line);
+        self.chunks[chunk].line(offset)
+    }
+
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = LineRef<'_>> {
+        self.range(0, self.len)
+    }
+
+    // Iterate over lines start to end, not including end
+    pub fn range(&self, start: usize, end: usize) -> impl DoubleEndedIterator<Item = LineRef<'_>> {
+        assert!(start <= end && end <= self.len, "line range out of bounds");
+
+        let (first, first_offset) = self.locate(start);
+        let (last, last_offset) = self.locate(end);
+        let chunks = if start == end {
+            &self.chunks[..0]
+        } else {
+            &self.chunks[first..=last]
+        };
+
+        chunks.iter().enumerate().flat_map(move |(i, chunk)| {
+            let from = if i == 0 { first_offset } else { 0 };
+            let to = if first + i == last {
+                last_offset
+            } else {
+                chunk.len()
+            };
+            (from..to).map(move |j| chunk.line(j))
+        })
+    }
+
+    // Copy lines start to end, not including end
+    pub fn copy(&self, start: usize, end: usize) -> Text {
+        let mut text = Text::new();
+        for line in self.range(start, end) {
+            text.push(line);
+        }
+        text
+    }
+
+    // Replace every line with the bytes f appends for it, keeping its line
+    // ending. Chunks are replaced one at a time, so the text isn't held in
+    // memory twice.
+    pub fn map_lines<E, F>(self, mut f: F) -> Result<Text, E>
+    where
+        F: FnMut(&[u8], &mut Vec<u8>) -> Result<(), E>,
+    {
+        let mut chunks = Vec::with_capacity(self.chunks.len());
+        for chunk in self.chunks {
+            let mut bytes = Vec::with_capacity(chunk.bytes.len());
+            let mut ends = Vec::with_capacity(chunk.len());
+            for i in 0..chunk.len() {
+                f(chunk.line(i).as_bytes(), &mut bytes)?;
+                ends.push(bytes.len());
+            }
+            chunks.push(Chunk {
+                bytes,
+                ends,
+                endings: chunk.endings,
+            });
+        }
+
+        Ok(Text {
+            chunks,
+            starts: self.starts,
+            len: self.len,
+        })
+    }
+
+    // Replace count lines starting at start with lines. Returns the removed
+    // lines.
+    pub fn splice(&mut self, start: usize, count: usize, lines: Text) -> Text {
+        assert!(start + count <= self.len, "line range out of bounds");
+
+        if self.chunks.is_empty() {
+            self.chunks.push(Chunk::default());
+            self.starts.push(0);
+        }
+
+        let (first, offset) = self.locate(start);
+        let (last, last_offset) = self.locate(start + count);
+        self.len = self.len - count + lines.len;
+
+        // Edits within a chunk, such as changing a line, are made in place
+        if first == last && lines.chunks.len() <= 1 {
+            let new = lines.chunks.into_iter().next().unwrap_or_default();
+            let mut removed = Text::new();
+            removed.push_chunk(self.chunks[first].replace(offset, last_offset, new));
+            self.rebalance(first, first + 1);
+            return removed;
+        }
+
+        // Otherwise cut the chunks the removed lines are in around them.
+        // Chunks entirely inside the removed lines are moved rather than
+        // copied.
+        let tail = self.chunks[last].split_off(last_offset);
+        let mut removed = Text::new();
+        removed.push_chunk(self.chunks[first].split_off(offset));
+
+        let mut inserted = lines.chunks;
+        inserted.push(tail);
+        let to = first + 1 + inserted.len();
+        for chunk in self.chunks.splice(first + 1..=last, inserted) {
+            removed.push_chunk(chunk);
+        }
+        self.rebalance(first, to);
+
+        removed
+    }
+
+    // Add a chunk of lines at the end
+    fn push_chunk(&mut self, chunk: Chunk) {
+        if chunk.len() > 0 {
+            self.starts.push(self.len);
+            self.len += chunk.len();
+            self.chunks.push(chunk);
+        }
+    }
+
+    // Find the chunk a line is in and its offset in the chunk. The end of the
+    // text is in the last chunk.
+    fn locate(&self, line: usize) -> (usize, usize) {
+        if self.chunks.is_empty() {
+            return (0, 0);
+        }
+
+        // Chunks are never empty, so no two chunks start at the same line
+        let chunk = match self.starts.binary_search(&line) {
+            Ok(i) => i,
+            Err(i) => i - 1,
+        };
+        (chunk, line - self.starts[chunk])
+    }
+
+    // Split or merge the chunks in from..to so they stay close to CHUNK_SIZE,
+    // then update where each chunk starts.
+    fn rebalance(&mut self, from: usize, to: usize) {
+        // Include the previous chunk so small chunks can merge into it
+        let from = from.saturating_sub(1);
+        let to = (to + 1).min(self.chunks.len());
+
+        // Chunks are taken out of the range and put back over it, so the
+        // chunks after it only move if the number of chunks changes
+        let mut chunks: Vec<Chunk> = Vec::with_capacity(to - from);
+        for chunk in &mut self.chunks[from..to] {
+            let mut chunk = std::mem::take(chunk);
+            match chunks.last_mut() {
+                _ if chunk.len() == 0 => {}
+                Some(last) if last.len() + chunk.len() <= CHUNK_SIZE => last.append(&chunk),
+                _ if chunk.len() > 2 * CHUNK_SIZE => {
+                    let mut pieces = Vec::new();
+                    while chunk.len() > CHUNK_SIZE {
+                        let len = chunk.len();
+                        pieces.push(chunk.split_off(len - CHUNK_SIZE));
+                    }
+                    chunks.push(chunk);
+                    chunks.extend(pieces.into_iter().rev());
+                }
+                _ => chunks.push(chunk),
+            }
+        }
+        self.chunks.splice(from..to, chunks);
+
+        self.starts.clear();
+        let mut start = 0;
+        for chunk in &self.chunks {
+            self.starts.push(start);
+            start += chunk.len();
+        }
+    }
+}
+
+impl Chunk {
+    fn len(&self) -> usize {
+        self.ends.len()
+    }
+
+    fn line(&self, i: usize) -> LineRef<'_> {
+        LineRef::new(&self.bytes[self.start(i)..self.ends[i]], self.endings[i])
+    }
+
+    fn push(&mut self, line: LineRef) {
+        self.bytes.extend_from_slice(line.as_bytes());
+        self.ends.push(self.bytes.len());
+        self.endings.push(line.ending());
+    }
+
+    // Byte offset of line i
+    fn start(&self, i: usize) -> usize {
+        if i == 0 {
+            0
+        } else {
+            self.ends[i - 1]
+        }
+    }
+
+    fn append(&mut self, other: &Chunk) {
+        let offset = self.bytes.len();
+        self.bytes.reserve_exact(other.bytes.len());
+        self.bytes.extend_from_slice(&other.bytes);
+        self.ends.extend(other.ends.iter().map(|end| end + offset));
+        self.endings.extend_from_slice(&other.endings);
+    }
+
+    // Replace lines from to to, not including to, with the lines of new.
+    // Returns the removed lines.
+    fn replace(&mut self, from: usize, to: usize, new: Chunk) -> Chunk {
+        let (start, end) = (self.start(from), self.start(to));
+        let removed = Chunk {
+            bytes: self.bytes[start..end].to_vec(),
+            ends: self.ends[from..to].iter().map(|e| e - start).collect(),
+            endings: self.endings[from..to].to_vec(),
+        };
+
+        // Leave a little room when a chunk grows rather than doubling it
+        let new_end = start + new.bytes.len();
+        let grow = (new_end + self.bytes.len() - end).saturating_sub(self.bytes.capacity());
+        if grow > 0 {
+            self.bytes.reserve_exact(grow.max(self.bytes.len() / 16));
+        }
+        self.bytes.splice(start..end, new.bytes);
+        self.ends
+            .splice(from..to, new.ends.iter().map(|e| e + start));
+        for e in &mut self.ends[from + new.endings.len()..] {
+            *e = *e - end + new_end;
+        }
+        self.endings.splice(from..to, new.endings);
+        removed
+    }
+
+    // Split the chunk in two at line at, returning the second half
+    fn split_off(&mut self, at: usize) -> Chunk {
+        if at == 0 {
+            return std::mem::take(self);
+        }
+
+        let offset = self.ends[at - 1];
+        let tail = Chunk {
+            bytes: self.bytes.split_off(offset),
+            ends: self.ends.split_off(at),
+            endings: self.endings.split_off(at),
+        };
+        let ends = tail.ends.into_iter().map(|end| end - offset).collect();
+        Chunk { ends, ..tail }
+    }
+}
+
+impl From<Vec<Line>> for Text {
+    fn from(lines: Vec<Line>) -> Text {
+        let mut text = Text::new();
+        for line in &lines {
+            text.push(line.as_line_ref());
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(range: std::ops::Range<usize>) -> Vec<Line> {
+        range.map(|i| Line::from(i.to_string())).collect()
+    }
+
+    fn numbered(range: std::ops::Range<usize>) -> Text {
+        Text::from(lines(range))
+    }
+
+    fn to_lines(text: &Text) -> Vec<Line> {
+        text.iter().map(|l| l.to_line()).collect()
+    }
+
+    // Check the text has the same lines as expected and its chunks are in
+    // order
+    fn check(text: &Text, expected: &[Line]) {
+        assert_eq!(text.len(), expected.len());
+        assert_eq!(to_lines(text), expected);
+
+        let mut start = 0;
+        for (chunk, chunk_start) in text.chunks.iter().zip(&text.starts) {
+            assert!(chunk.len() > 0 || text.chunks.len() == 1);
+            assert!(chunk.len() <= 2 * CHUNK_SIZE);
+            assert_eq!(chunk.ends.last().copied().unwrap_or(0), chunk.bytes.len());
+            assert_eq!(*chunk_start, start);
+            start += chunk.len();
+        }
+        assert_eq!(text.chunks.len(), text.starts.len());
+    }
+
+    #[test]
+    fn push_fills_chunks() {
+        let text = numbered(0..2 * CHUNK_SIZE + 1);
+        assert_eq!(text.chunks.len(), 3);
+        assert_eq!(text.starts, [0, CHUNK_SIZE, 2 * CHUNK_SIZE]);
+        check(&text, &lines(0..2 * CHUNK_SIZE + 1));
+    }
+
+    #[test]
+    fn locate() {
+        let text = numbered(0..2 * CHUNK_SIZE + 10);
+        assert_eq!(text.locate(0), (0, 0));
+        assert_eq!(text.locate(CHUNK_SIZE - 1), (0, CHUNK_SIZE - 1));
+        assert_eq!(text.locate(CHUNK_SIZE), (1, 0));
+        assert_eq!(text.locate(2 * CHUNK_SIZE + 5), (2, 5));
+        // The end of the text is in the last chunk
+        assert_eq!(text.locate(text.len()), (2, 10));
+        assert_eq!(Text::new().locate(0), (0, 0));
+
+        for i in [0, 1, CHUNK_SIZE - 1, CHUNK_SIZE, 2 * CHUNK_SIZE + 9] {
+            assert_eq!(text.line(i).to_line(), Line::from(i.to_string()));
+        }
+    }
+
+    #[test]
+    fn splice() {
+        let mut text = Text::new();
+        let mut expected = Vec::new();
+
+        // Into an empty text
+        assert!(text.splice(0, 0, numbered(0..3)).is_empty());
+        expected.extend(lines(0..3));
+        check(&text, &expected);
+
+        // Insert enough lines into one chunk that it has to be split
+        text.splice(1, 0, numbered(100..100 + 3 * CHUNK_SIZE));
+        expected.splice(1..1, lines(100..100 + 3 * CHUNK_SIZE));
+        check(&text, &expected);
+        assert!(text.chunks.len() > 1);
+
+        // Replace lines across chunk boundaries
+        let start = CHUNK_SIZE - 5;
+        let removed = text.splice(start, CHUNK_SIZE + 10, numbered(7..9));
+        let expected_removed: Vec<Line> = expected
+            .splice(start..start + CHUNK_SIZE + 10, lines(7..9))
+            .collect();
+        assert_eq!(to_lines(&removed), expected_removed);
+        check(&text, &expected);
+
+        // Append at the end
+        let len = text.len();
+        text.splice(len, 0, numbered(0..1));
+        expected.extend(lines(0..1));
+        check(&text, &expected);
+
+        // Remove everything
+        let removed = text.splice(0, text.len(), Text::new());
+        assert_eq!(to_lines(&removed), expected);
+        check(&text, &[]);
+        assert!(text.iter().next().is_none());
+    }
+
+    // Lines changed inside a chunk are replaced in place and keep their
+    // neighbours' bytes and endings intact
+    #[test]
+    fn splice_within_chunk() {
+        let mut text = numbered(0..10);
+        let mut expected = lines(0..10);
+
+        let mut long = Line::from("a much longer line than before");
+        long.set_ending(Some(LineEnding::CrLf));
+        let removed = text.splice(3, 2, Text::from(vec![long.clone()]));
+        assert_eq!(to_lines(&removed), &expected[3..5]);
+        expected.splice(3..5, [long]);
+        check(&text, &expected);
+        assert_eq!(text.line(3).ending(), Some(LineEnding::CrLf));
+
+        let removed = text.splice(0, 1, numbered(20..23));
+        assert_eq!(to_lines(&removed), &expected[0..1]);
+        expected.splice(0..1, lines(20..23));
+        check(&text, &expected);
+    }
+
+    #[test]
+    fn map_lines() {
+        let text = numbered(0..3 * CHUNK_SIZE);
+        let mapped = text
+            .map_lines(|line, out| {
+                out.extend_from_slice(line);
+                out.push(b'!');
+                Ok::<_, ()>(())
+            })
+            .unwrap();
+        let expected: Vec<Line> = (0..3 * CHUNK_SIZE)
+            .map(|i| Line::from(format!("{}!", i)))
+            .collect();
+        check(&mapped, &expected);
+    }
+
+    // Random splices of every size give the same lines as splicing a Vec
+    #[test]
+    fn random_splices() {
+        let mut text = Text::new();
+        let mut expected = Vec::new();
+        let mut seed = 0x2545_f491_4f6c_dd1du64;
+        let mut random = |n: usize| {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            (seed % (n as u64 + 1)) as usize
+        };
+
+        for i in 0..500 {
+            let start = random(text.len());
+            let count = random((text.len() - start).min(3 * CHUNK_SIZE));
+            let new = lines(i * 10_000..i * 10_000 + random(3 * CHUNK_SIZE));
+            let removed = text.splice(start, count, Text::from(new.clone()));
+            let expected_removed: Vec<Line> = expected.splice(start..start + count, new).collect();
+            assert_eq!(to_lines(&removed), expected_removed);
+        }
+        check(&text, &expected);
+    }
+
+    // Many small deletes leave small chunks that get merged back together
+    #[test]
+    fn rebalance_merges_small_chunks() {
+        let mut text = numbered(0..8 * CHUNK_SIZE);
+        let mut expected = lines(0..8 * CHUNK_SIZE);
+
+        let mut i = 0;
+        while i < text.len() {
+            text.splice(i, 3, Text::new());
+            expected.drain(i..i + 3);
+            i += 1;
+        }
+        check(&text, &expected);
+        assert!(text.chunks.len() <= 3);
+    }
+
+    #[test]
+    fn range() {
+        let text = numbered(0..3 * CHUNK_SIZE);
+        let expected = lines(0..3 * CHUNK_SIZE);
+
+        for (start, end) in [
+            (0, 0),
+            (5, 5),
+            (0, 10),
+            (CHUNK_SIZE - 2, CHUNK_SIZE + 2),
+            (10, 2 * CHUNK_SIZE + 10),
+            (0, 3 * CHUNK_SIZE),
+            (3 * CHUNK_SIZE, 3 * CHUNK_SIZE),
+        ] {
+            let slice = &expected[start..end];
+            assert!(text
+                .range(start, end)
+                .eq(slice.iter().map(Line::as_line_ref)));
+            assert!(text
+                .range(start, end)
+                .rev()
+                .eq(slice.iter().rev().map(Line::as_line_ref)));
+            assert_eq!(to_lines(&text.copy(start, end)), slice);
+        }
+    }
+}
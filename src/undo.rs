@@ -0,0 +1,121 @@
+use std::collections::VecDeque;
+use std::path::PathBuf;
+
+use crate::format::FileFormat;
+use crate::text::Text;
+
+pub const DEFAULT_DEPTH: usize = 100;
+
+// A single reversible change. Each change describes how to revert itself,
+// reverting produces the change that will redo it.
+pub enum Change {
+    // Replace count lines starting at line with lines
+    Splice {
+        line: u32,
+        count: usize,
+        lines: Text,
+    },
+    // Restore the file the buffer is associated with and its format
+    File {
+        filename: Option<PathBuf>,
+        format: FileFormat,
+    },
+    // Restore the buffer's line endings
+    Format(FileFormat),
+}
+
+// All changes made by one command
+pub struct Step {
+    pub changes: Vec<Change>,
+    pub line_before: u32,
+    pub line_after: u32,
+}
+
+pub struct History {
+    undo: VecDeque<Step>,
+    redo: Vec<Step>,
+    pending: Option<Step>,
+    depth: usize,
+}
+
+impl History {
+    pub fn new(depth: usize) -> Self {
+        History {
+            undo: VecDeque::with_capacity(depth.min(DEFAULT_DEPTH)),
+            redo: Vec::new(),
+            pending: None,
+            depth,
+        }
+    }
+
+    pub fn set_depth(&mut self, depth: usize) {
+        self.depth = depth;
+        self.trim();
+    }
+
+    // Start grouping changes into a single undo step.
+    pub fn begin(&mut self, curr_line: u32) {
+        self.pending = Some(Step {
+            changes: Vec::new(),
+            line_before: curr_line,
+            line_after: curr_line,
+        });
+    }
+
+    // True between begin and commit
+    pub fn in_step(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    pub fn record(&mut self, change: Change) {
+        if let Some(step) = &mut self.pending {
+            step.changes.push(change);
+        }
+    }
+
+    // Finish the current step. Steps without changes are dropped.
+    pub fn commit(&mut self, curr_line: u32) {
+        let mut step = match self.pending.take() {
+            Some(s) if !s.changes.is_empty() => s,
+            _ => return,
+        };
+
+        step.line_after = curr_line;
+        self.redo.clear();
+        self.undo.push_back(step);
+        self.trim();
+    }
+
+    // Forget everything that can be undone or redone, including changes the
+    // current step has made so far.
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+        if let Some(step) = &mut self.pending {
+            step.changes.clear();
+        }
+    }
+
+    pub fn pop_undo(&mut self) -> Option<Step> {
+        self.undo.pop_back()
+    }
+
+    pub fn pop_redo(&mut self) -> Option<Step> {
+        self.redo.pop()
+    }
+
+    pub fn push_undo(&mut self, step: Step) {
+        self.undo.push_back(step);
+        self.trim();
+    }
+
+    pub fn push_redo(&mut self, step: Step) {
+        self.redo.push(step);
+    }
+
+    fn trim(&mut self) {
+        while self.undo.len() > self.depth {
+            self.undo.pop_front();
+        }
+    }
+}